@@ -7,6 +7,6 @@ for i in "${!PORTS[@]}"; do
     --cwd "$(pwd)" \
     --title "Node ${PORTS[$i]}" \
     --hold \
-    -- zsh -c "source ~/.zshrc && cargo run -- --port ${PORTS[$i]}"
+    -- zsh -c "source ~/.zshrc && cargo run -- --p2p-port ${PORTS[$i]} --lan-discovery"
   sleep 0.5
 done
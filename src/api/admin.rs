@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::http::StatusCode;
+use warp::Filter;
+use std::net::IpAddr;
+use std::sync::Arc;
+use crate::api::common::{error, no_content, reply, with_node};
+use crate::blockchain::block::Block;
+use crate::p2p::bans::{Ban, BAN_DURATION};
+use crate::p2p::message::MessageData;
+use crate::p2p::node::Node;
+use crate::p2p::peer::PeerInfo;
+
+#[derive(Debug, Deserialize)]
+struct BanRequest {
+  ip:       String,
+  // Seconds until the ban is lifted.
+  duration: Option<u64>,
+  reason:   Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct BansReply {
+  bans: Vec<Ban>,
+}
+
+#[derive(Clone, Serialize)]
+struct PeersReply {
+  peers: Vec<PeerInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectRequest {
+  addr: String,
+}
+
+#[derive(Clone, Serialize)]
+struct ConnectReply {
+  peer_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+  message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChainQuery {
+  from:  Option<u64>,
+  limit: Option<u64>,
+}
+
+#[derive(Clone, Serialize)]
+struct ChainReply {
+  height: u64,
+  blocks: Vec<Block>,
+}
+
+/**
+ * Routes for running the node. Every request needs the admin token as a
+ * bearer token, and none are served when no token is configured.
+ */
+pub fn admin_routes(node: Arc<Node>, token: Option<String>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let authorized = warp::header::optional::<String>("authorization")
+    .and_then(move |header: Option<String>| {
+      let token = token.clone();
+
+      async move {
+        match (token, header) {
+          (Some(token), Some(header)) if same_secret(&header, &format!("Bearer {}", token)) => Ok(()),
+          _ => Err(warp::reject::not_found()),
+        }
+      }
+    })
+    .untuple_one();
+
+  let list_peers = warp::path!("peers")
+    .and(warp::get())
+    .and(with_node(node.clone()))
+    .and_then(handle_peer_list);
+
+  let connect_peer = warp::path!("peers")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_node(node.clone()))
+    .and_then(handle_peer_connect);
+
+  let chat = warp::path!("chat")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_node(node.clone()))
+    .and_then(handle_chat);
+
+  let sync = warp::path!("sync")
+    .and(warp::post())
+    .and(with_node(node.clone()))
+    .and_then(handle_sync);
+
+  let chain = warp::path!("chain")
+    .and(warp::get())
+    .and(warp::query::<ChainQuery>())
+    .and(with_node(node.clone()))
+    .and_then(handle_chain);
+
+  let list_bans = warp::path!("bans")
+    .and(warp::get())
+    .and(with_node(node.clone()))
+    .and_then(handle_ban_list);
+
+  let create_ban = warp::path!("bans")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_node(node.clone()))
+    .and_then(handle_ban_create);
+
+  let delete_ban = warp::path!("bans" / String)
+    .and(warp::delete())
+    .and(with_node(node.clone()))
+    .and_then(handle_ban_delete);
+
+  // The token is checked before the routes, so without it they don't exist.
+  warp::path("admin")
+    .and(authorized)
+    .and(list_peers
+      .or(connect_peer)
+      .or(chat)
+      .or(sync)
+      .or(chain)
+      .or(list_bans)
+      .or(create_ban)
+      .or(delete_ban))
+}
+
+/**
+ * Compare secrets in constant time. Both are hashed first, so that the time
+ * taken doesn't give away the length of the secret either.
+ */
+fn same_secret(given: &str, secret: &str) -> bool {
+  Sha256::digest(given)
+    .ct_eq(&Sha256::digest(secret))
+    .into()
+}
+
+/**
+ * Handle listing the connected peers.
+ */
+async fn handle_peer_list(node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  reply(&PeersReply {
+    peers: node.get_peer_infos().await,
+  })
+}
+
+/**
+ * Handle connecting to a peer, and syncing from it.
+ */
+async fn handle_peer_connect(req: ConnectRequest, node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  match node.join(&req.addr).await {
+    Ok(peer_id) => reply(&ConnectReply { peer_id }),
+    Err(e)      => error(&e.to_string(), StatusCode::BAD_GATEWAY),
+  }
+}
+
+/**
+ * Handle sending a chat message to every peer.
+ */
+async fn handle_chat(req: ChatRequest, node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  node.yell(&MessageData::Chat {
+    message: req.message,
+  }).await;
+
+  no_content()
+}
+
+/**
+ * Handle syncing the chain from a random peer.
+ */
+async fn handle_sync(node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  node.sync().await;
+
+  no_content()
+}
+
+/**
+ * Handle listing the blocks of the chain, from a height on.
+ */
+async fn handle_chain(query: ChainQuery, node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  let reader = node.chain.reader();
+  let height = reader.height();
+
+  let from = query.from.unwrap_or(0);
+  let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+  let blocks = (from..=height)
+    .take(limit as usize)
+    .filter_map(|index| reader.at(index as usize))
+    .collect();
+
+  reply(&ChainReply {
+    height,
+    blocks,
+  })
+}
+
+/**
+ * Handle listing the active bans.
+ */
+async fn handle_ban_list(node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  reply(&BansReply {
+    bans: node.bans.lock().await.list(),
+  })
+}
+
+/**
+ * Handle banning an address, which disconnects its peers.
+ */
+async fn handle_ban_create(req: BanRequest, node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(ip) = req.ip.parse::<IpAddr>() else {
+    return error("Invalid IP address.", StatusCode::BAD_REQUEST);
+  };
+
+  let reason = req.reason.unwrap_or_else(|| "banned by admin".to_string());
+
+  node.ban(ip, req.duration.unwrap_or(BAN_DURATION), &reason).await;
+
+  no_content()
+}
+
+/**
+ * Handle lifting a ban.
+ */
+async fn handle_ban_delete(ip: String, node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(ip) = ip.parse::<IpAddr>() else {
+    return error("Invalid IP address.", StatusCode::BAD_REQUEST);
+  };
+
+  if !node.unban(&ip).await {
+    return error("Address is not banned.", StatusCode::NOT_FOUND);
+  }
+
+  no_content()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn test_admin_routes_need_the_token() {
+      let node = Arc::new(Node::temp().await);
+
+      let routes = admin_routes(node.clone(), Some("secret".to_string()));
+      let reply = warp::test::request()
+        .path("/admin/bans")
+        .header("authorization", "Bearer guess")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+
+      let routes = admin_routes(node, None);
+      let reply = warp::test::request()
+        .path("/admin/bans")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_listed() {
+      let alice = Arc::new(Node::temp().await);
+      let bob = Arc::new(Node::temp().await);
+
+      let accepting = tokio::spawn({
+        let bob = bob.clone();
+        async move {
+          let (stream, _) = bob.listener.accept().await.unwrap();
+          bob.handle_incoming(stream).await.unwrap();
+        }
+      });
+
+      alice.connect_to_peer(&bob.get_local_addr()).await.unwrap();
+      accepting.await.unwrap();
+
+      let routes = admin_routes(alice.clone(), Some("secret".to_string()));
+      let reply = warp::test::request()
+        .path("/admin/peers")
+        .header("authorization", "Bearer secret")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::OK);
+
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      let peer = &json["peers"][0];
+
+      assert_eq!(peer["peer_id"], bob.node_id);
+      assert_eq!(peer["direction"], "outbound");
+      assert_eq!(peer["addr"], bob.get_local_addr());
+      assert_eq!(peer["tip_height"], 0);
+      assert_eq!(peer["score"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_chain_is_listed() {
+      let node = Arc::new(Node::temp().await);
+      let routes = admin_routes(node.clone(), Some("secret".to_string()));
+
+      let reply = warp::test::request()
+        .path("/admin/chain?from=0&limit=10")
+        .header("authorization", "Bearer secret")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::OK);
+
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      assert_eq!(json["height"], 0);
+      assert_eq!(json["blocks"][0]["hash"], node.genesis_hash());
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_connected() {
+      let alice = Arc::new(Node::temp().await);
+      let bob = Arc::new(Node::temp().await);
+      let routes = admin_routes(alice.clone(), Some("secret".to_string()));
+
+      let accepting = tokio::spawn({
+        let bob = bob.clone();
+        async move {
+          let (stream, _) = bob.listener.accept().await.unwrap();
+          bob.handle_incoming(stream).await.unwrap();
+        }
+      });
+
+      let reply = warp::test::request()
+        .method("POST")
+        .path("/admin/peers")
+        .header("authorization", "Bearer secret")
+        .json(&serde_json::json!({ "addr": bob.get_local_addr() }))
+        .reply(&routes)
+        .await;
+      accepting.await.unwrap();
+
+      assert_eq!(reply.status(), StatusCode::OK);
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      assert_eq!(json["peer_id"], bob.node_id);
+
+      let reply = warp::test::request()
+        .method("POST")
+        .path("/admin/chat")
+        .header("authorization", "Bearer secret")
+        .json(&serde_json::json!({ "message": "hello" }))
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_bans_are_managed() {
+      let node = Arc::new(Node::temp().await);
+      let routes = admin_routes(node.clone(), Some("secret".to_string()));
+
+      let reply = warp::test::request()
+        .method("POST")
+        .path("/admin/bans")
+        .header("authorization", "Bearer secret")
+        .json(&serde_json::json!({ "ip": "10.0.0.1", "reason": "spam" }))
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+      assert!(node.is_banned(&"10.0.0.1".parse().unwrap()).await);
+
+      let reply = warp::test::request()
+        .path("/admin/bans")
+        .header("authorization", "Bearer secret")
+        .reply(&routes)
+        .await;
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      assert_eq!(json["bans"][0]["ip"], "10.0.0.1");
+      assert_eq!(json["bans"][0]["reason"], "spam");
+
+      let reply = warp::test::request()
+        .method("DELETE")
+        .path("/admin/bans/10.0.0.1")
+        .header("authorization", "Bearer secret")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+      assert!(!node.is_banned(&"10.0.0.1".parse().unwrap()).await);
+    }
+}
@@ -1,21 +1,26 @@
-use tokio::sync::Mutex;
 use tokio::net::TcpListener;
 use warp::Filter;
 use serde::Serialize;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use crate::blockchain::chain::Blockchain;
+use tracing::{error, info, warn};
+use crate::blockchain::handle::ChainHandle;
+use crate::p2p::node::Node;
+use crate::api::admin::admin_routes;
+use crate::api::metrics::{metric_routes, observe_request};
 use crate::api::posts::post_routes;
 use crate::api::users::user_routes;
 use crate::api::links::link_routes;
+use crate::api::search::search_routes;
+use crate::api::tags::tag_routes;
 
 #[derive(Clone, Serialize)]
 struct HealthReply {}
 
 /**
- * Start the API.
+ * Start the API, and serve it until the node is stopped.
  */
-pub async fn start_api(chain: Arc<Mutex<Blockchain>>, addr: String) {
+pub async fn start_api(chain: ChainHandle, node: Arc<Node>, addr: String, admin_token: Option<String>) {
   let addr: SocketAddr = addr.parse().unwrap();
 
   let health = warp::path("health")
@@ -25,31 +30,45 @@ pub async fn start_api(chain: Arc<Mutex<Blockchain>>, addr: String) {
   let user_routes = user_routes(chain.clone());
   let post_routes = post_routes(chain.clone());
   let link_routes = link_routes();
+  let search_routes = search_routes(chain.clone());
+  let tag_routes = tag_routes(chain.clone());
+  let admin_routes = admin_routes(node.clone(), admin_token);
+  let metric_routes = metric_routes(node.clone());
 
   let routes = health
     .or(user_routes)
     .or(post_routes)
     .or(link_routes)
+    .or(search_routes)
+    .or(tag_routes)
+    .or(admin_routes)
+    .or(metric_routes)
     .with(warp::cors()
       .allow_any_origin() // Allow any origin (for development)
       .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
-      .allow_headers(vec!["Content-Type"])
+      .allow_headers(vec!["Content-Type", "Authorization"])
     )
-    .recover(handle_rejection);
+    .recover(handle_rejection)
+    .with(warp::log::custom(observe_request))
+    .with(warp::trace::request());
 
   match TcpListener::bind(addr).await {
     Ok(listener) => {
       drop(listener);
 
-      println!("Running API on {}", addr);
+      info!(%addr, "Running API");
 
-      warp::serve(routes).run(addr).await;
+      let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, async move {
+        node.stopped().await;
+      });
+
+      server.await;
     }
     Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
-      println!("API already running on {}, skipping startup.", addr);
+      warn!(%addr, "API already running, skipping startup");
     }
     Err(e) => {
-      eprintln!("Failed to bind server: {}", e);
+      error!(%addr, error = %e, "Failed to bind server");
     }
   }
 }
@@ -4,8 +4,10 @@ use warp::http;
 use warp::reply::{Json, WithStatus};
 use http::StatusCode;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::blockchain::chain::Blockchain;
+use crate::blockchain::handle::ChainHandle;
+use crate::blockchain::reader::PooledIndex;
+use crate::blockchain::page::{Cursor, Order, PageRequest};
+use crate::p2p::node::Node;
 
 #[derive(Clone, Serialize)]
 pub struct ErrorReply {
@@ -13,11 +15,27 @@ pub struct ErrorReply {
 }
 
 pub fn with_chain(
-  chain: Arc<Mutex<Blockchain>>,
-) -> impl Filter<Extract = (Arc<Mutex<Blockchain>>,), Error = std::convert::Infallible> + Clone {
+  chain: ChainHandle,
+) -> impl Filter<Extract = (ChainHandle,), Error = std::convert::Infallible> + Clone {
   warp::any().map(move || chain.clone())
 }
 
+pub fn with_node(
+  node: Arc<Node>,
+) -> impl Filter<Extract = (Arc<Node>,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || node.clone())
+}
+
+/**
+ * Borrow a read-only connection to the index for a request.
+ */
+pub fn read_index(chain: &ChainHandle) -> Result<PooledIndex, warp::Rejection> {
+  chain
+    .reader()
+    .index()
+    .map_err(|_| warp::reject::reject())
+}
+
 /**
  * Create an error response with the given message and status code.
  */
@@ -48,3 +66,30 @@ pub fn no_content() -> Result<WithStatus<Json>, warp::Rejection> {
     StatusCode::NO_CONTENT
   ))
 }
+
+/**
+ * Build a page request for a list in the given order from the `before`,
+ * `after` and `limit` query parameters. Returns `None` if a cursor is invalid,
+ * belongs to a list in another order or both are given.
+ */
+pub fn page_request(order: Order, before: Option<String>, after: Option<String>, limit: Option<usize>) -> Option<PageRequest> {
+  let decode = |cursor: Option<String>| match cursor {
+    Some(cursor) => Cursor::decode(&cursor)
+      .filter(|cursor| cursor.order() == order)
+      .map(Some),
+    None => Some(None),
+  };
+
+  let before = decode(before)?;
+  let after  = decode(after)?;
+
+  if before.is_some() && after.is_some() {
+    return None;
+  }
+
+  Some(PageRequest {
+    before,
+    after,
+    limit: limit.unwrap_or(32).clamp(1, 100),
+  })
+}
@@ -0,0 +1,135 @@
+use warp::Filter;
+use std::fmt::Write;
+use std::sync::Arc;
+use crate::api::common::with_node;
+use crate::metrics::{header, metrics};
+use crate::p2p::node::Node;
+use crate::p2p::peer::Direction;
+
+// Path segments that name a route, rather than being a value in one.
+const ROUTE_SEGMENTS: &[&str] = &[
+  "admin", "bans", "chain", "chat", "feed", "h", "hashtags", "health", "link_preview",
+  "mentions", "metrics", "peers", "posts", "s", "search", "sync", "thread", "trending",
+  "users",
+];
+
+// Longer paths match no route.
+const ROUTE_DEPTH: usize = 4;
+
+pub fn metric_routes(node: Arc<Node>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path!("metrics")
+    .and(warp::get())
+    .and(with_node(node))
+    .and_then(handle_metrics)
+}
+
+/**
+ * Handle a scrape, in the Prometheus text format.
+ */
+async fn handle_metrics(node: Arc<Node>) -> Result<impl warp::Reply, warp::Rejection> {
+  let reader = node.chain.reader();
+  let height = reader.height();
+  let mempool = reader.mempool_size();
+
+  let peers = node.get_peer_infos().await;
+  let inbound = peers
+    .iter()
+    .filter(|peer| peer.direction == Direction::Inbound)
+    .count();
+
+  // The chain is synced when it is as high as the highest peer.
+  let target = peers
+    .iter()
+    .map(|peer| peer.tip_height)
+    .max()
+    .unwrap_or(0)
+    .max(height);
+
+  let progress = match target {
+    0      => 1.0,
+    target => height as f64 / target as f64,
+  };
+
+  let mut out = String::new();
+
+  gauge(&mut out, "cryptogram_chain_height", "The height of the top block.", height);
+  gauge(&mut out, "cryptogram_mempool_size", "Blocks waiting to be mined.", mempool);
+  gauge(&mut out, "cryptogram_mining_hash_rate", "Hashes per second over the last block mined.", node.miner.hash_rate());
+  gauge(&mut out, "cryptogram_sync_target_height", "The highest tip of the chain or any peer.", target);
+  gauge(&mut out, "cryptogram_sync_progress", "The chain height as a share of the sync target.", progress);
+
+  header(&mut out, "cryptogram_peers", "gauge", "Connected peers, by who dialed.");
+  let _ = writeln!(out, "cryptogram_peers{{direction=\"inbound\"}} {}", inbound);
+  let _ = writeln!(out, "cryptogram_peers{{direction=\"outbound\"}} {}", peers.len() - inbound);
+
+  metrics().render(&mut out);
+
+  Ok(warp::reply::with_header(out, "content-type", "text/plain; version=0.0.4"))
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+  header(out, name, "gauge", help);
+  let _ = writeln!(out, "{} {}", name, value);
+}
+
+/**
+ * Count the latency of an API request against its route.
+ */
+pub fn observe_request(info: warp::log::Info) {
+  metrics().observe_request(
+    info.method().as_str(),
+    &route(info.path()),
+    info.status().as_u16(),
+    info.elapsed(),
+  );
+}
+
+/**
+ * The route of a request path, with the values in it, like hashes and
+ * usernames, replaced so that every request to a route is counted together.
+ */
+fn route(path: &str) -> String {
+  let segments: Vec<&str> = path
+    .split('/')
+    .filter(|segment| !segment.is_empty())
+    .map(|segment| if ROUTE_SEGMENTS.contains(&segment) { segment } else { ":param" })
+    .collect();
+
+  if segments.len() > ROUTE_DEPTH {
+    return "unmatched".to_string();
+  }
+
+  format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_leave_out_values() {
+      assert_eq!(route("/posts/ab12/thread"), "/posts/:param/thread");
+      assert_eq!(route("/users/h/alice/mentions"), "/users/h/:param/mentions");
+      assert_eq!(route("/admin/bans/10.0.0.1"), "/admin/bans/:param");
+      assert_eq!(route("/feed"), "/feed");
+      assert_eq!(route("/a/b/c/d/e"), "unmatched");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_scraped() {
+      let node = Arc::new(Node::temp().await);
+
+      let reply = warp::test::request()
+        .path("/metrics")
+        .reply(&metric_routes(node))
+        .await;
+      assert_eq!(reply.status(), warp::http::StatusCode::OK);
+
+      let body = String::from_utf8(reply.body().to_vec()).unwrap();
+      assert!(body.contains("cryptogram_chain_height 0\n"));
+      assert!(body.contains("cryptogram_mempool_size 0\n"));
+      assert!(body.contains("cryptogram_sync_progress 1\n"));
+      assert!(body.contains("cryptogram_peers{direction=\"inbound\"} 0\n"));
+      assert!(body.contains("# TYPE cryptogram_blocks_accepted_total counter\n"));
+    }
+}
@@ -1,5 +1,10 @@
 pub mod common;
+#[allow(clippy::module_inception)]
 pub mod api;
 pub mod users;
 pub mod posts;
 pub mod links;
+pub mod search;
+pub mod tags;
+pub mod admin;
+pub mod metrics;
@@ -1,13 +1,13 @@
-use tokio::sync::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_qs;
 use warp::http::StatusCode;
 use warp::Filter;
-use std::sync::Arc;
-use crate::blockchain::chain::Blockchain;
+use crate::blockchain::handle::ChainHandle;
 use crate::blockchain::block::{BlockData, PendingBlock};
 use crate::blockchain::index::PostDetail;
-use crate::api::common::{error, reply, no_content, with_chain};
+use crate::blockchain::text;
+use crate::blockchain::page::Order;
+use crate::api::common::{error, read_index, reply, no_content, page_request, with_chain};
 
 #[derive(Clone, Deserialize)]
 pub struct PostRequest {
@@ -20,16 +20,26 @@ pub struct PostRequest {
 #[derive(Debug, Deserialize)]
 struct FeedQuery {
   user:   Option<Vec<String>>,
+  before: Option<String>,
+  after:  Option<String>,
   limit:  Option<usize>,
-  offset: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ThreadQuery {
+  depth: Option<usize>,
+  after: Option<String>,
+  limit: Option<usize>,
 }
 
 #[derive(Clone, Serialize)]
 struct FeedReply {
   feed: Vec<PostDetail>,
+  next: Option<String>,
+  prev: Option<String>,
 }
 
-pub fn post_routes(chain: Arc<Mutex<Blockchain>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn post_routes(chain: ChainHandle) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
   let feed = warp::path("feed")
     .and(warp::get())
     .and(warp::query::raw())
@@ -47,79 +57,121 @@ pub fn post_routes(chain: Arc<Mutex<Blockchain>>) -> impl Filter<Extract = impl
     .and(with_chain(chain.clone()))
     .and_then(handle_post_detail);
 
+  let post_thread = warp::path!("posts" / String / "thread")
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_post_thread);
+
   feed
     .or(post_create)
     .or(post_detail)
+    .or(post_thread)
 }
 
 /**
  * Handle the feed endpoint.
  */
-async fn handle_feed(query: String, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let query = serde_qs::from_str::<FeedQuery>(&query)
-    .unwrap();
+async fn handle_feed(query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<FeedQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
 
-  let chain = chain.lock().await;
-  let feed  = chain.index.get_feed(
-    query.user.unwrap_or(vec![]),
-    query.limit.unwrap_or(32),
-    query.offset.unwrap_or(0)
-  ).unwrap();
+  let Some(page) = page_request(Order::Chronological, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
 
-  let feed = chain.index
-    .hydrate_feed(feed)
-    .map_err(|_| warp::reject::reject())?;
+  let index = read_index(&chain)?;
+  let feed  = index
+    .get_feed(query.user.unwrap_or(vec![]), &page)
+    .and_then(|feed| feed.try_map(|posts| index.hydrate_feed(posts)));
+
+  let Ok(feed) = feed else {
+    return error("Could not read the feed.", StatusCode::INTERNAL_SERVER_ERROR);
+  };
 
   reply(&FeedReply {
-    feed
+    feed: feed.items,
+    next: feed.next,
+    prev: feed.prev,
   })
 }
 
 /**
  * Handle a new post being made.
  */
-async fn handle_post_create(req: PostRequest, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let mut chain = chain.lock().await;
-
-  // todo: validate reply hash
-
-  if req.body.len() > 300 {
+async fn handle_post_create(req: PostRequest, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  if text::length(&req.body) > 300 {
     return error("Post body cannot exceed 300 characters.", StatusCode::UNPROCESSABLE_ENTITY);
   }
 
-  chain.push_mempool(PendingBlock::new(
+  let pending = PendingBlock::new(
     BlockData::Post {
       body:   req.clone().body,
       reply:  req.clone().reply,
     },
     req.public_key,
     req.signature,
-  )).unwrap_or_else(|e| println!("{}", e));
+  );
+
+  let pushed = chain
+    .write(move |chain| chain.push_mempool(pending))
+    .await;
 
-  no_content()
+  match pushed {
+    Ok(())   => no_content(),
+    Err(msg) => error(&msg, StatusCode::UNPROCESSABLE_ENTITY),
+  }
 }
 
 /**
  * Handle a post detail.
  */
-async fn handle_post_detail(hash: String, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let chain = chain.lock().await;
+async fn handle_post_detail(hash: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let index = read_index(&chain)?;
 
-  let post = chain.index.get_post(&hash)
+  let post = index.get_post(&hash)
     .map_err(|_| warp::reject::not_found())?
-    .ok_or_else(|| warp::reject::not_found())?;
+    .ok_or_else(warp::reject::not_found)?;
 
-  let hydrated = chain.index.hydrate_post(post)
+  let hydrated = index.hydrate_post(post)
     .map_err(|_| warp::reject::not_found())?;
 
   reply(&hydrated)
 }
 
+/**
+ * Handle a thread view. This holds the posts the post replies to up to the
+ * root of the thread, and `depth` levels of replies under it.
+ */
+async fn handle_post_thread(hash: String, query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<ThreadQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
+
+  let Some(page) = page_request(Order::Chronological, None, query.after, query.limit.or(Some(10))) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let depth = query.depth
+    .unwrap_or(3)
+    .min(10);
+
+  let index = read_index(&chain)?;
+
+  match index.get_thread(&hash, depth, &page) {
+    Ok(Some(thread)) => reply(&thread),
+    Ok(None)         => error("Post could not be found.", StatusCode::NOT_FOUND),
+    Err(_)           => Err(warp::reject::reject()),
+  }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use warp::http::StatusCode;
     use warp::Reply;
+    use crate::blockchain::chain::Blockchain;
 
     #[tokio::test]
     async fn test_handle_post_create_rejects_long_post() {
@@ -130,7 +182,7 @@ mod tests {
         signature:  "dummy_sig".to_string(),
       };
 
-      let chain = Blockchain::new_arc();
+      let chain = ChainHandle::start(Blockchain::temp());
       let reply = handle_post_create(req, chain)
         .await
         .unwrap()
@@ -138,4 +190,21 @@ mod tests {
 
       assert_eq!(reply.status(), StatusCode::UNPROCESSABLE_ENTITY);
     }
+
+    #[tokio::test]
+    async fn test_feed_rejects_malformed_query() {
+      let chain = ChainHandle::start(Blockchain::temp());
+
+      let reply = handle_feed("limit=many".to_string(), chain.clone())
+        .await
+        .unwrap()
+        .into_response();
+      assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+
+      let reply = handle_feed(String::new(), chain)
+        .await
+        .unwrap()
+        .into_response();
+      assert_eq!(reply.status(), StatusCode::OK);
+    }
 }
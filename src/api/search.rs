@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use serde_qs;
+use warp::http::StatusCode;
+use warp::Filter;
+use crate::blockchain::handle::ChainHandle;
+use crate::blockchain::index::{PostDetail, PostSearch, User};
+use crate::blockchain::page::Order;
+use crate::api::common::{error, read_index, reply, page_request, with_chain};
+
+#[derive(Debug, Deserialize)]
+struct PostSearchQuery {
+  q:      Option<String>,
+  author: Option<String>,
+  since:  Option<u64>,
+  until:  Option<u64>,
+  before: Option<String>,
+  after:  Option<String>,
+  limit:  Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserSearchQuery {
+  q:      Option<String>,
+  before: Option<String>,
+  after:  Option<String>,
+  limit:  Option<usize>,
+}
+
+#[derive(Clone, Serialize)]
+struct PostSearchReply {
+  posts: Vec<PostDetail>,
+  next:  Option<String>,
+  prev:  Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct UserSearchReply {
+  users: Vec<User>,
+  next:  Option<String>,
+  prev:  Option<String>,
+}
+
+pub fn search_routes(chain: ChainHandle) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let search_posts = warp::path!("search" / "posts")
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_search_posts);
+
+  let search_users = warp::path!("search" / "users")
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_search_users);
+
+  search_posts
+    .or(search_users)
+}
+
+/**
+ * Handle a full-text search over posts.
+ */
+async fn handle_search_posts(query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<PostSearchQuery>(&query) else {
+    return error("Invalid search query.", StatusCode::BAD_REQUEST);
+  };
+
+  let Some(q) = query.q.filter(|q| !q.trim().is_empty()) else {
+    return error("Search query cannot be empty.", StatusCode::UNPROCESSABLE_ENTITY);
+  };
+
+  let Some(page) = page_request(Order::Ranked, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let index = read_index(&chain)?;
+  let posts = index.search_posts(&PostSearch {
+    query:  q,
+    author: query.author,
+    since:  query.since,
+    until:  query.until,
+    page,
+  }).map_err(|_| warp::reject::reject())?;
+
+  let posts = posts
+    .try_map(|posts| index.hydrate_feed(posts))
+    .map_err(|_| warp::reject::reject())?;
+
+  reply(&PostSearchReply {
+    posts: posts.items,
+    next:  posts.next,
+    prev:  posts.prev,
+  })
+}
+
+/**
+ * Handle a search over usernames, display names and biographies.
+ */
+async fn handle_search_users(query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<UserSearchQuery>(&query) else {
+    return error("Invalid search query.", StatusCode::BAD_REQUEST);
+  };
+
+  let Some(q) = query.q.filter(|q| !q.trim().is_empty()) else {
+    return error("Search query cannot be empty.", StatusCode::UNPROCESSABLE_ENTITY);
+  };
+
+  let Some(page) = page_request(Order::Ranked, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let index = read_index(&chain)?;
+  let users = index
+    .search_users(&q, &page)
+    .map_err(|_| warp::reject::reject())?;
+
+  reply(&UserSearchReply {
+    users: users.items,
+    next:  users.next,
+    prev:  users.prev,
+  })
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use serde_qs;
+use warp::http::StatusCode;
+use warp::Filter;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::blockchain::handle::ChainHandle;
+use crate::blockchain::index::{PostDetail, Trend};
+use crate::blockchain::page::Order;
+use crate::api::common::{error, read_index, reply, page_request, with_chain};
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+  before: Option<String>,
+  after:  Option<String>,
+  limit:  Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrendingQuery {
+  window: Option<u64>,
+  limit:  Option<usize>,
+}
+
+#[derive(Clone, Serialize)]
+struct PostsReply {
+  posts: Vec<PostDetail>,
+  next:  Option<String>,
+  prev:  Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct TrendingReply {
+  window:   u64,
+  hashtags: Vec<Trend>,
+}
+
+pub fn tag_routes(chain: ChainHandle) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let trending = warp::path!("hashtags" / "trending")
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_trending);
+
+  let hashtag_posts = warp::path!("hashtags" / String)
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_hashtag_posts);
+
+  let mentions = warp::path!("users" / "h" / String / "mentions")
+    .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(with_chain(chain.clone()))
+    .and_then(handle_mentions);
+
+  trending
+    .or(hashtag_posts)
+    .or(mentions)
+}
+
+/**
+ * Handle listing the posts tagged with a hashtag.
+ */
+async fn handle_hashtag_posts(tag: String, query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<PageQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
+
+  let Some(page) = page_request(Order::Chronological, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let index = read_index(&chain)?;
+  let posts = index
+    .get_hashtag_posts(&tag, &page)
+    .map_err(|_| warp::reject::reject())?;
+
+  let posts = posts
+    .try_map(|posts| index.hydrate_feed(posts))
+    .map_err(|_| warp::reject::reject())?;
+
+  reply(&PostsReply {
+    posts: posts.items,
+    next:  posts.next,
+    prev:  posts.prev,
+  })
+}
+
+/**
+ * Handle listing the posts that mention a user.
+ */
+async fn handle_mentions(username: String, query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<PageQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
+
+  let Some(page) = page_request(Order::Chronological, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let index = read_index(&chain)?;
+  let posts = index
+    .get_mentions(&username, &page)
+    .map_err(|_| warp::reject::reject())?;
+
+  let posts = posts
+    .try_map(|posts| index.hydrate_feed(posts))
+    .map_err(|_| warp::reject::reject())?;
+
+  reply(&PostsReply {
+    posts: posts.items,
+    next:  posts.next,
+    prev:  posts.prev,
+  })
+}
+
+/**
+ * Handle listing the trending hashtags. The window is given in seconds and
+ * defaults to a day.
+ */
+async fn handle_trending(query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<TrendingQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
+
+  let window = query.window
+    .unwrap_or(24 * 60 * 60)
+    .min(30 * 24 * 60 * 60);
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards")
+    .as_secs();
+
+  let index = read_index(&chain)?;
+  let hashtags = index.trending_hashtags(
+    now.saturating_sub(window),
+    query.limit.unwrap_or(10).min(100),
+  ).map_err(|_| warp::reject::reject())?;
+
+  reply(&TrendingReply {
+    window,
+    hashtags,
+  })
+}
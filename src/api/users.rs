@@ -1,12 +1,12 @@
-use tokio::sync::Mutex;
 use serde::{Deserialize, Serialize};
 use warp::http;
 use warp::Filter;
-use std::sync::Arc;
 use http::StatusCode;
-use crate::blockchain::chain::Blockchain;
+use crate::blockchain::handle::ChainHandle;
 use crate::blockchain::block::{BlockData, PendingBlock};
-use crate::api::common::{error, reply, with_chain};
+use crate::blockchain::index::User;
+use crate::blockchain::page::Order;
+use crate::api::common::{error, read_index, reply, no_content, page_request, with_chain};
 
 #[derive(Clone, Deserialize)]
 pub struct UserCreateRequest {
@@ -25,12 +25,26 @@ pub struct UserUpdateRequest {
   signature:    String,
 }
 
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+  before: Option<String>,
+  after:  Option<String>,
+  limit:  Option<usize>,
+}
+
 #[derive(Clone, Serialize)]
 pub struct ErrorReply {
   message: String,
 }
 
-pub fn user_routes(chain: Arc<Mutex<Blockchain>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+#[derive(Clone, Serialize)]
+struct UserSearchReply {
+  users: Vec<User>,
+  next:  Option<String>,
+  prev:  Option<String>,
+}
+
+pub fn user_routes(chain: ChainHandle) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
   let create_user = warp::path("users")
     .and(warp::post())
     .and(warp::body::json())
@@ -55,6 +69,7 @@ pub fn user_routes(chain: Arc<Mutex<Blockchain>>) -> impl Filter<Extract = impl
 
   let user_search = warp::path!("users" / "s" / String)
     .and(warp::get())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
     .and(with_chain(chain.clone()))
     .and_then(handle_user_search);
 
@@ -68,18 +83,22 @@ pub fn user_routes(chain: Arc<Mutex<Blockchain>>) -> impl Filter<Extract = impl
 /**
  * Handle user registration.
  */
-async fn handle_user_create(req: UserCreateRequest, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let mut chain = chain.lock().await;
+async fn handle_user_create(req: UserCreateRequest, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let index = read_index(&chain)?;
 
-  if chain.index.has_username(&req.username).unwrap() {
-    return error("Username is already taken.", StatusCode::UNPROCESSABLE_ENTITY);
+  match index.has_username(&req.username) {
+    Ok(true)  => return error("Username is already taken.", StatusCode::UNPROCESSABLE_ENTITY),
+    Ok(false) => {},
+    Err(_)    => return error("Could not look up the username.", StatusCode::INTERNAL_SERVER_ERROR),
   }
 
-  if chain.index.has_pubkey(&req.public_key).unwrap() {
-    return error("Public key is already taken.", StatusCode::UNPROCESSABLE_ENTITY);
+  match index.has_pubkey(&req.public_key) {
+    Ok(true)  => return error("Public key is already taken.", StatusCode::UNPROCESSABLE_ENTITY),
+    Ok(false) => {},
+    Err(_)    => return error("Could not look up the public key.", StatusCode::INTERNAL_SERVER_ERROR),
   }
 
-  chain.push_mempool(PendingBlock::new(
+  let pending = PendingBlock::new(
     BlockData::User {
       display_name: req.display_name,
       username:     req.username,
@@ -87,46 +106,48 @@ async fn handle_user_create(req: UserCreateRequest, chain: Arc<Mutex<Blockchain>
     },
     req.public_key,
     req.signature,
-  )).unwrap_or_else(|e| println!("{}", e));
-
-  Ok(
-    warp::reply::with_status(
-      warp::reply::json(&{}),
-      StatusCode::NO_CONTENT
-    )
-  )
-}
+  );
 
-async fn handle_user_update(public_key: String, req: UserUpdateRequest, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let mut chain = chain.lock().await;
+  let pushed = chain
+    .write(move |chain| chain.push_mempool(pending))
+    .await;
+
+  match pushed {
+    Ok(())   => no_content(),
+    Err(msg) => error(&msg, StatusCode::UNPROCESSABLE_ENTITY),
+  }
+}
 
+async fn handle_user_update(public_key: String, req: UserUpdateRequest, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
   if public_key != req.public_key {
     return error("Public key does not match.", StatusCode::UNAUTHORIZED);
   }
 
-  chain.push_mempool(PendingBlock::new(
+  let pending = PendingBlock::new(
     BlockData::UserUpdate {
       display_name: req.display_name,
       biography:    req.biography,
     },
     public_key,
     req.signature,
-  )).unwrap_or_else(|e| println!("{}", e));
-
-  Ok(
-    warp::reply::with_status(
-      warp::reply::json(&{}),
-      StatusCode::NO_CONTENT
-    )
-  )
+  );
+
+  let pushed = chain
+    .write(move |chain| chain.push_mempool(pending))
+    .await;
+
+  match pushed {
+    Ok(())   => no_content(),
+    Err(msg) => error(&msg, StatusCode::UNPROCESSABLE_ENTITY),
+  }
 }
 
 /**
  * Handle user details.
  */
-async fn handle_user_by_name(username: String, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let chain = chain.lock().await;
-  let user = chain.index.get_user_by_username(&username);
+async fn handle_user_by_name(username: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let index = read_index(&chain)?;
+  let user = index.get_user_by_username(&username);
 
   match user {
     Ok(Some(user)) => reply(&user),
@@ -135,9 +156,9 @@ async fn handle_user_by_name(username: String, chain: Arc<Mutex<Blockchain>>) ->
   }
 }
 
-async fn handle_user_by_pkey(public_key: String, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let chain = chain.lock().await;
-  let user = chain.index.get_user_by_public_key(&public_key);
+async fn handle_user_by_pkey(public_key: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let index = read_index(&chain)?;
+  let user = index.get_user_by_public_key(&public_key);
 
   match user {
     Ok(Some(user)) => reply(&user),
@@ -149,13 +170,25 @@ async fn handle_user_by_pkey(public_key: String, chain: Arc<Mutex<Blockchain>>)
 /**
  * Handle user searches.
  */
-async fn handle_user_search(search: String, chain: Arc<Mutex<Blockchain>>) -> Result<impl warp::Reply, warp::Rejection> {
-  let chain = chain.lock().await;
-  let users = chain.index.search_users(search);
+async fn handle_user_search(search: String, query: String, chain: ChainHandle) -> Result<impl warp::Reply, warp::Rejection> {
+  let Ok(query) = serde_qs::from_str::<PageQuery>(&query) else {
+    return error("Invalid query.", StatusCode::BAD_REQUEST);
+  };
 
-  users
-    .map(|users| warp::reply::json(&users))
-    .map_err(|_| warp::reject::not_found())
+  let Some(page) = page_request(Order::Ranked, query.before, query.after, query.limit) else {
+    return error("Invalid page cursor.", StatusCode::BAD_REQUEST);
+  };
+
+  let index = read_index(&chain)?;
+  let users = index
+    .search_users(&search, &page)
+    .map_err(|_| warp::reject::reject())?;
+
+  reply(&UserSearchReply {
+    users: users.items,
+    next:  users.next,
+    prev:  users.prev,
+  })
 }
 
 #[cfg(test)]
@@ -165,6 +198,9 @@ mod tests {
     use warp::Reply;
     use warp::hyper::body::to_bytes;
     use serde_json::Value;
+    use crate::blockchain::block::Block;
+    use crate::blockchain::chain::Blockchain;
+    use crate::blockchain::page::Cursor;
 
     #[tokio::test]
     async fn test_handle_user_post_rejects_existing_username() {
@@ -176,8 +212,15 @@ mod tests {
         signature:    "dummy_sig".to_string(),
       };
 
-      // todo: this is using the real blockchain. it must use a test one
-      let chain = Blockchain::new_arc();
+      let chain = Blockchain::temp();
+
+      chain.index.add_block(Block::new(BlockData::User {
+        display_name: "Hampus Backman".to_string(),
+        username:     "hbackman".to_string(),
+        biography:    "".to_string(),
+      }, 1, "0".to_string())).unwrap();
+
+      let chain = ChainHandle::start(chain);
       let reply = handle_user_create(req, chain)
         .await
         .unwrap()
@@ -190,4 +233,53 @@ mod tests {
 
       assert_eq!(json["message"], "Username is already taken.");
     }
+
+    #[tokio::test]
+    async fn test_user_search_is_paged() {
+      let chain = Blockchain::temp();
+
+      for i in 0..3 {
+        let mut block = Block::new(BlockData::User {
+          display_name: format!("Alice {}", i),
+          username:     format!("alice{}", i),
+          biography:    "".to_string(),
+        }, i + 1, "0".to_string());
+
+        block.public_key = format!("key-{}", i);
+        chain.index.add_block(block).unwrap();
+      }
+
+      let routes = user_routes(ChainHandle::start(chain));
+
+      let reply = warp::test::request()
+        .path("/users/s/alice?limit=2")
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::OK);
+
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      assert_eq!(json["users"].as_array().unwrap().len(), 2);
+
+      let reply = warp::test::request()
+        .path(&format!("/users/s/alice?before={}", json["next"].as_str().unwrap()))
+        .reply(&routes)
+        .await;
+
+      let json: Value = serde_json::from_slice(reply.body()).unwrap();
+      assert_eq!(json["users"].as_array().unwrap().len(), 1);
+      assert!(json["next"].is_null());
+
+      // A cursor from a chronological list.
+      let cursor = Cursor::Chain {
+        height:    1,
+        timestamp: 0,
+        hash:      "00".to_string(),
+      };
+
+      let reply = warp::test::request()
+        .path(&format!("/users/s/alice?before={}", cursor.encode()))
+        .reply(&routes)
+        .await;
+      assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+    }
 }
@@ -2,6 +2,8 @@ use serde::{Serialize, Deserialize};
 use serde_json::Value;
 use sha2::{Sha256, Digest};
 use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+use crate::blockchain::genesis::GenesisAccount;
 use crate::blockchain::sign::ValidationError;
 use crate::blockchain::sign::validate_signature;
 
@@ -44,8 +46,6 @@ impl PendingBlock {
    * Validate the block signature.
    */
   pub fn validate_signature(&self) -> Result<(), ValidationError> {
-    println!("data: {}", &self.data.to_string_for_signing());
-
     validate_signature(
       &self.public_key,
       &self.signature,
@@ -57,28 +57,7 @@ impl PendingBlock {
    * Validate the block size.
    */
   pub fn validate_size(&self) -> Result<(), String> {
-    match &self.data {
-      BlockData::Post { body, .. } => {
-        if body.len() > 300 {
-          return Err("Post size exceeds 300 characters.".to_string());
-        }
-      },
-      BlockData::User { username, display_name, biography, .. } => {
-        if username.len() > 255 {
-          return Err("Username exceeds 255 characters.".to_string());
-        }
-
-        if display_name.len() > 255 {
-          return Err("Display name exceeds 255 characters.".to_string());
-        }
-
-        if biography.len() > 300 {
-          return Err("Biography exceeds 300 characters.".to_string());
-        }
-      }
-      _ => {}
-    }
-    Ok(())
+    self.data.rules().validate_limits(&self.data)
   }
 }
 
@@ -86,7 +65,10 @@ impl PendingBlock {
 #[serde(tag = "type")]
 pub enum BlockData {
   Genesis {
-    //
+    #[serde(default)]
+    network:  String,
+    #[serde(default)]
+    accounts: Vec<GenesisAccount>,
   },
   User {
     display_name: String,
@@ -127,6 +109,34 @@ impl BlockData {
   }
 }
 
+/**
+ * Check if a string is formatted like a block hash.
+ */
+pub fn is_hash(hash: &str) -> bool {
+  hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/**
+ * Hash a nonce between the parts of a hash template.
+ */
+pub fn hash_nonce(prefix: &Sha256, suffix: &[u8], nonce: u64) -> [u8; 32] {
+  let mut hasher = prefix.clone();
+  hasher.update(nonce.to_string());
+  hasher.update(suffix);
+  hasher.finalize().into()
+}
+
+/**
+ * Check if a hash starts with `difficulty` zeros in hex.
+ */
+pub fn meets_difficulty(hash: &[u8], difficulty: usize) -> bool {
+  let bytes = difficulty / 2;
+
+  hash.len() * 2 >= difficulty
+    && hash[..bytes].iter().all(|b| *b == 0)
+    && (difficulty.is_multiple_of(2) || hash[bytes] >> 4 == 0)
+}
+
 impl Block {
   pub fn new(data: BlockData, index: u64, previous_hash: String) -> Self {
     let timestamp = SystemTime::now()
@@ -156,29 +166,59 @@ impl Block {
   }
 
   pub fn hash_block(&self) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(self.index.to_string());
-    hasher.update(self.timestamp.to_string());
-    hasher.update(self.nonce.to_string());
-    hasher.update(self.data.to_json());
-    hasher.update(self.signature.to_string());
-    hasher.update(self.public_key.to_string());
-    hasher.update(&self.prev_hash);
-    format!("{:x}", hasher.finalize())
+    let (prefix, suffix) = self.hash_template();
+    hex::encode(hash_nonce(&prefix, &suffix, self.nonce))
+  }
+
+  /**
+   * Split the hash input around the nonce. The hasher has consumed the fields
+   * before the nonce, and the bytes after it are serialized once, so mining
+   * only hashes the nonce and the suffix for every attempt.
+   */
+  pub fn hash_template(&self) -> (Sha256, Vec<u8>) {
+    let mut prefix = Sha256::new();
+    prefix.update(self.index.to_string());
+    prefix.update(self.timestamp.to_string());
+
+    let mut suffix = self.data.to_json().into_bytes();
+    suffix.extend_from_slice(self.signature.as_bytes());
+    suffix.extend_from_slice(self.public_key.as_bytes());
+    suffix.extend_from_slice(self.prev_hash.as_bytes());
+
+    (prefix, suffix)
   }
 
   /**
    * Mine the block until the hash hits the difficulty.
    */
-  pub fn mine_block(&mut self) {
-    let target = "0".repeat(self.difficulty());
+  pub fn mine_block(&mut self, difficulty: usize) {
+    let (prefix, suffix) = self.hash_template();
 
-    while !self.hash.starts_with(&target) {
+    while !meets_difficulty(&hash_nonce(&prefix, &suffix, self.nonce), difficulty) {
       self.nonce += 1;
-      self.hash = self.hash_block();
     }
 
-    println!("Block mined! Nonce: {}, Hash: {}", self.nonce, self.hash);
+    self.hash = self.hash_block();
+
+    debug!(hash = %self.hash, nonce = self.nonce, "Block mined");
+  }
+
+  /**
+   * Validate that the hash is the hash of the block, and that it meets the
+   * base difficulty of the block type. The chain may ask for more work.
+   */
+  pub fn validate_work(&self) -> Result<(), String> {
+    if self.hash != self.hash_block() {
+      return Err("Block hash does not match its contents.".to_string());
+    }
+
+    let hash = hex::decode(&self.hash).map_err(|e| e.to_string())?;
+
+    if !meets_difficulty(&hash, self.data.rules().difficulty) {
+      return Err("Block hash did not meet difficulty.".to_string());
+    }
+
+    Ok(())
   }
 
   /**
@@ -196,16 +236,4 @@ impl Block {
       &self.data.to_string_for_signing()
     )
   }
-
-  /**
-   * The block difficulty.
-   */
-  pub fn difficulty(&self) -> usize {
-    // User registration should be a little more difficult than other blocks to
-    // prevent rapid registration attempts.
-    match self.data {
-      BlockData::User {..} => 5,
-      _                    => 3,
-    }
-  }
 }
@@ -1,42 +1,154 @@
-use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::watch;
+use tracing::{info, info_span, warn};
 use crate::blockchain::store::Store;
 use crate::blockchain::index::Index;
-use crate::blockchain::block::{Block, BlockData, PendingBlock};
+use crate::blockchain::genesis::Genesis;
+use crate::blockchain::block::{is_hash, Block, BlockData, PendingBlock};
+use crate::blockchain::reader::ChainReader;
+use crate::metrics::metrics;
+use crate::blockchain::rules::{is_username, post_work, Signer, POST_LIMIT, POST_WINDOW};
 
 #[derive(Debug)]
 pub struct Blockchain {
-  pub mpool: Vec<PendingBlock>,
+  mpool:     Vec<PendingBlock>,
   pub store: Store,
   pub index: Index,
+  // The size of the memory pool, for readers.
+  mpool_size: Arc<AtomicUsize>,
+  // The hash of the top block, so miners can tell when their work is stale.
+  tip: watch::Sender<String>,
+  // Where the memory pool is kept while the node is down.
+  mpool_path: PathBuf,
 }
 
 impl Blockchain {
-  pub fn new() -> Self {
+  /**
+   * Open the chain stored in the given directory. A new chain starts with the
+   * genesis block, an existing one has to have been started with it.
+   */
+  pub fn open(dir: &Path, genesis: &Genesis) -> Result<Self, String> {
+    genesis.validate()?;
+
     let mut chain = Self {
-      mpool: vec![],
-      store: Store::new().unwrap(),
-      index: Index::new(),
+      mpool:      vec![],
+      mpool_size: Arc::default(),
+      store:      Store::open(dir.join("blockchain")).map_err(|e| e.to_string())?,
+      index:      Index::open(dir.join("chainindex.db")).map_err(|e| e.to_string())?,
+      tip:        watch::Sender::new(String::new()),
+      mpool_path: dir.join("mempool.json"),
     };
 
-    chain.add_block(Blockchain::genesis())
-      .unwrap_or_else(|e| println!("{}", e));
+    let block = genesis.block();
+
+    match chain.at(0) {
+      Some(existing) if existing.hash != block.hash => {
+        return Err(format!(
+          "The chain in '{}' was not started from the {} genesis block.",
+          dir.display(),
+          genesis.network,
+        ));
+      },
+      Some(_) => {},
+      None => {
+        // An index left over from another chain.
+        if chain.index.height().map_err(|e| e.to_string())?.is_some() {
+          chain.index.clear().map_err(|e| e.to_string())?;
+        }
+
+        chain.add_block(block)?;
+      },
+    }
+
+    chain.catch_up_index()
+      .map_err(|e| format!("Could not catch the index up with the chain: {}", e))?;
+
+    chain.tip.send_replace(chain.top_block().hash);
+    chain.load_mempool();
+
+    Ok(chain)
+  }
+
+  /**
+   * Close the chain, keeping the memory pool for the next run and flushing
+   * the store and the index to disk.
+   */
+  pub fn close(self) -> Result<(), String> {
+    self.save_mempool().map_err(|e| format!("Could not save the memory pool: {}", e))?;
+
+    self.store.env
+      .force_sync()
+      .map_err(|e| e.to_string())?;
 
-    // Catch the index up.
-    for block in chain.chain_iter() {
-      let _ = chain.index.add_block(block);
+    self.index
+      .close()
+      .map_err(|e| e.to_string())
+  }
+
+  /**
+   * Write the memory pool to disk. An empty pool removes the file.
+   */
+  fn save_mempool(&self) -> std::io::Result<()> {
+    if self.mpool.is_empty() {
+      return match fs::remove_file(&self.mpool_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+      };
     }
 
-    chain
+    let tmp = self.mpool_path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&self.mpool)?)?;
+    fs::rename(tmp, &self.mpool_path)
   }
 
-  pub fn new_arc() -> Arc<Mutex<Self>> {
-    Arc::new(Mutex::new(Self::new()))
+  /**
+   * Put the memory pool saved by the last run back, dropping the blocks that
+   * are no longer valid. The file is removed, so the blocks are only loaded
+   * once.
+   */
+  fn load_mempool(&mut self) {
+    let Ok(json) = fs::read_to_string(&self.mpool_path) else {
+      return;
+    };
+
+    let _ = fs::remove_file(&self.mpool_path);
+
+    let pending = serde_json::from_str::<Vec<PendingBlock>>(&json).unwrap_or_default();
+
+    for block in pending {
+      if let Err(e) = self.push_mempool(block) {
+        warn!(error = %e, "Dropping saved pending block");
+      }
+    }
   }
 
-  fn genesis() -> Block {
-    Block::new(BlockData::Genesis {}, 0, "0".to_string())
+  /**
+   * Read access to the chain next to this writer.
+   */
+  pub fn reader(&self) -> ChainReader {
+    let path = self.index
+      .path()
+      .expect("Readers need an index file.");
+
+    ChainReader::new(self.store.clone(), path, self.mpool_size.clone())
+  }
+
+  /**
+   * Open a new chain in a temporary directory.
+   */
+  #[cfg(test)]
+  pub fn temp() -> Self {
+    Self::open(&crate::blockchain::temp_dir(), &Genesis::profile("devnet").unwrap()).unwrap()
+  }
+
+  /**
+   * The hash of the genesis block, which identifies the network.
+   */
+  pub fn genesis_hash(&self) -> String {
+    self.at(0).unwrap().hash
   }
 
   /**
@@ -46,6 +158,32 @@ impl Blockchain {
     self.store.get_height().unwrap() as usize
   }
 
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /**
+   * Index the blocks that are in storage but not yet in the index. After a
+   * rebuild this replays the whole chain. The index stops at the first block
+   * it can't take, so it never skips one.
+   */
+  fn catch_up_index(&self) -> Result<(), String> {
+    let from = match self.index.height().map_err(|e| e.to_string())? {
+      Some(height) => height + 1,
+      None         => 0,
+    };
+
+    for i in from..=self.store.get_height().map_err(|e| e.to_string())? {
+      if let Some(block) = self.at(i as usize) {
+        self.index
+          .add_block(block)
+          .map_err(|e| format!("block {}: {}", i, e))?;
+      }
+    }
+
+    Ok(())
+  }
+
   /**
    * Retrieve a block at the given index.
    */
@@ -59,27 +197,164 @@ impl Blockchain {
    * Add a block to the chain.
    */
   pub fn add_block(&mut self, block: Block) -> Result<(), String> {
-    if block.index > 0 {
-      block.validate_signature().map_err(|e| e.to_string())?;
+    let _span = info_span!("block", hash = %block.hash, index = block.index).entered();
+
+    // Only the genesis block starts a chain, and it was checked against the
+    // network when the chain was opened. Every later block is validated.
+    let genesis = block.index == 0 && self.at(0).is_none();
+
+    if !genesis {
+      if let Err((reason, e)) = self.validate_block(&block) {
+        metrics().block_rejected(reason);
+        return Err(e);
+      }
+    }
+
+    let hash = block.hash.clone();
+
+    self.store
+      .put_block(block)
+      .map_err(|e| format!("Could not store the block: {}", e))?;
 
-      self.validate_hash(&block)?;
-      self.validate_user(&block)?;
+    // The index is behind until it takes every block, and is caught up again
+    // with the next one.
+    if let Err(e) = self.catch_up_index() {
+      warn!(error = %e, "Could not index the block");
     }
 
-    let _ = self.store.put_block(block.clone());
-    let _ = self.index.add_block(block);
+    self.tip.send_replace(hash);
+
+    metrics().block_accepted();
+    info!("Added block");
 
     Ok(())
   }
 
+  /**
+   * Validate a block against the chain. Errors come with the kind of check
+   * that failed, for counting rejections.
+   */
+  fn validate_block(&self, block: &Block) -> Result<(), (&'static str, String)> {
+    block.validate_signature().map_err(|e| ("signature", e.to_string()))?;
+    block.validate_work().map_err(|e| ("work", e))?;
+
+    // The rules below depend on the height, so it has to be the next one.
+    self.validate_height(block).map_err(|e| ("height", e))?;
+    self.validate_hash(block).map_err(|e| ("chain", e))?;
+    self.validate_transaction(&block.data, &block.public_key, block.index)
+      .map_err(|e| ("transaction", e))
+  }
+
+  /**
+   * Watch the hash of the top block.
+   */
+  pub fn subscribe_tip(&self) -> watch::Receiver<String> {
+    self.tip.subscribe()
+  }
+
+  /**
+   * Remove the blocks above the given height, reverting the chain state and
+   * the index. This is how the node backs out of a fork.
+   */
+  pub fn rollback(&mut self, height: u64) -> Result<(), String> {
+    while self.store.get_height().map_err(|e| e.to_string())? > height {
+      let Some(block) = self.store.pop_block().map_err(|e| e.to_string())? else {
+        break;
+      };
+
+      self.index.remove_block(&block).map_err(|e| e.to_string())?;
+
+      // A profile update replaced the profile set by an earlier block.
+      if let BlockData::UserUpdate { .. } = block.data {
+        let previous = self.find_profile(&block.public_key).map(|b| b.data);
+
+        if let Some(
+          BlockData::User { display_name, biography, .. } |
+          BlockData::UserUpdate { display_name, biography }
+        ) = previous {
+          self.index
+            .set_profile(&block.public_key, &display_name, &biography)
+            .map_err(|e| e.to_string())?;
+        }
+      }
+    }
+
+    self.tip.send_replace(self.top_block().hash);
+
+    Ok(())
+  }
+
+  /**
+   * Find the latest block that set the profile of an account.
+   */
+  fn find_profile(&self, public_key: &str) -> Option<Block> {
+    let registered = self.store
+      .get_account(public_key)
+      .ok()??
+      .registered;
+
+    (registered..=self.store.get_height().ok()?)
+      .rev()
+      .filter_map(|i| self.store.get_block(i).ok().flatten())
+      .find(|block| block.public_key == public_key && matches!(
+        block.data,
+        BlockData::User { .. } | BlockData::UserUpdate { .. }
+      ))
+  }
+
   /**
    * Add a block to the memory pool.
    */
   pub fn push_mempool(&mut self, block: PendingBlock) -> Result<(), String> {
     block.validate_signature().map_err(|e| e.to_string())?;
-    block.validate_size()?;
 
+    // The block can't be mined before the next height.
+    let height = self.store.get_height().unwrap() + 1;
+    self.validate_transaction(&block.data, &block.public_key, height)?;
+
+    if let BlockData::Post { .. } = block.data {
+      let pending = self.mpool
+        .iter()
+        .filter(|p| p.public_key == block.public_key && matches!(p.data, BlockData::Post { .. }))
+        .count();
+
+      self.validate_post_rate(&block.public_key, height, pending)?;
+    }
+
+    self.mpool.push(block);
+    self.mpool_size.store(self.mpool.len(), Ordering::Relaxed);
+
+    Ok(())
+  }
+
+  /**
+   * Take the latest block out of the memory pool.
+   */
+  pub fn pop_mempool(&mut self) -> Option<PendingBlock> {
+    let block = self.mpool.pop();
+    self.mpool_size.store(self.mpool.len(), Ordering::Relaxed);
+    block
+  }
+
+  /**
+   * Put a block taken out of the memory pool back, without checking it
+   * again.
+   */
+  pub fn requeue_mempool(&mut self, block: PendingBlock) {
     self.mpool.push(block);
+    self.mpool_size.store(self.mpool.len(), Ordering::Relaxed);
+  }
+
+  /**
+   * Validate that the block goes on top of the chain, and doesn't replace one
+   * that is already on it.
+   */
+  fn validate_height(&self, block: &Block) -> Result<(), String> {
+    let height = self.store.get_height().map_err(|e| e.to_string())?;
+
+    if block.index != height + 1 {
+      return Err(format!("Block index {} does not follow the chain height {}.", block.index, height));
+    }
 
     Ok(())
   }
@@ -89,7 +364,7 @@ impl Blockchain {
    * was met during block mining.
    */
   fn validate_hash(&self, block: &Block) -> Result<(), String> {
-    let target = "0".repeat(block.difficulty());
+    let target = "0".repeat(self.difficulty(block)?);
     let lblock = self.top_block();
 
     if block.prev_hash != lblock.hash {
@@ -104,79 +379,539 @@ impl Blockchain {
   }
 
   /**
-   * Validate that the user is registered before they are allowed to create a
-   * new block. This only applies for `Post` block data.
+   * Validate a transaction against the rules of its type, as if it was mined
+   * at the given height.
    */
-  fn validate_user(&self, block: &Block) -> Result<(), String> {
-    let mut user_names: HashSet<String> = HashSet::new();
-    let mut user_pkeys: HashSet<String> = HashSet::new();
+  fn validate_transaction(&self, data: &BlockData, public_key: &str, height: u64) -> Result<(), String> {
+    let rules = data.rules();
 
-    for i in 0..=self.store.get_height().unwrap() {
-      let block = self.store.get_block(i)
-        .unwrap()
-        .unwrap();
+    rules.validate_limits(data)?;
+    self.validate_signer(rules.signer, public_key)?;
 
-      if let BlockData::User { username, .. } = &block.data {
-        user_names.insert(username.clone());
-        user_pkeys.insert(block.public_key.clone());
-      }
+    (rules.validate)(self, data, public_key, height)
+  }
+
+  /**
+   * The difficulty a block has to be mined at. This is the base difficulty of
+   * the transaction type, and for posts the anti-spam work on top of it.
+   */
+  pub fn difficulty(&self, block: &Block) -> Result<usize, String> {
+    let base = block.data.rules().difficulty;
+
+    match &block.data {
+      BlockData::Post { body, .. } => {
+        Ok(base + post_work(self.recent_posts(&block.public_key, block.index)?, body))
+      },
+      _ => Ok(base),
     }
+  }
 
-    // Validate user registration.
-    if let BlockData::User { username, .. } = block.data.clone() {
-      if user_names.contains(&username) {
-        return Err(format!("Username '{}' is already taken.", username));
-      }
+  /**
+   * Count the posts of an account in the recent blocks below the given height.
+   */
+  fn recent_posts(&self, public_key: &str, height: u64) -> Result<usize, String> {
+    self.store
+      .count_posts(public_key, height.saturating_sub(POST_WINDOW), height)
+      .map_err(|e| format!("Could not count recent posts: {}", e))
+  }
 
-      if user_pkeys.contains(&block.public_key) {
-        return Err(format!("Public key '{}' is already registered.", block.public_key));
-      }
+  /**
+   * Validate that an account stays under the post limit of the anti-spam
+   * policy, counting the posts it has pending as well.
+   */
+  pub(crate) fn validate_post_rate(&self, public_key: &str, height: u64, pending: usize) -> Result<(), String> {
+    if self.recent_posts(public_key, height)? + pending >= POST_LIMIT {
+      return Err(format!("Public key '{}' has posted too much recently.", public_key));
+    }
 
+    Ok(())
+  }
+
+  /**
+   * Validate that the signer is allowed to create the transaction.
+   */
+  fn validate_signer(&self, signer: Signer, public_key: &str) -> Result<(), String> {
+    let registered = self.store
+      .get_account(public_key)
+      .map_err(|e| e.to_string())?
+      .is_some();
+
+    match signer {
+      Signer::Nobody => {
+        Err("Only the first block can be a genesis block.".to_string())
+      },
+      Signer::Unregistered if registered => {
+        Err(format!("Public key '{}' is already registered.", public_key))
+      },
+      Signer::Registered if !registered => {
+        Err(format!("Public key '{}' is not registered.", public_key))
+      },
+      _ => Ok(()),
+    }
+  }
+
+  /**
+   * Validate that a registration claims a well formed username that is still
+   * free, ignoring case.
+   */
+  pub(crate) fn validate_username(&self, data: &BlockData) -> Result<(), String> {
+    let BlockData::User { username, .. } = data else {
       return Ok(());
+    };
+
+    if !is_username(username) {
+      return Err("Username must be 3 to 30 letters, digits or underscores.".to_string());
     }
 
-    // Validate post.
-    if let BlockData::Post {..} = block.data.clone() {
-      if !user_pkeys.contains(&block.public_key) {
-        return Err(format!("Public key '{}' is not registered.", block.public_key));
-      }
+    let taken = self.store
+      .get_username_owner(username)
+      .map_err(|e| e.to_string())?
+      .is_some();
 
-      return Ok(());
+    if taken {
+      return Err(format!("Username '{}' is already taken.", username));
     }
 
     Ok(())
   }
 
+  /**
+   * Validate that a reply refers to a post in an earlier block than the one
+   * at the given height.
+   */
+  pub(crate) fn validate_reply(&self, data: &BlockData, height: u64) -> Result<(), String> {
+    let BlockData::Post { reply: Some(reply), .. } = data else {
+      return Ok(());
+    };
+
+    if !is_hash(reply) {
+      return Err(format!("Reply '{}' is not a post hash.", reply));
+    }
+
+    match self.index.post_height(reply).map_err(|e| e.to_string())? {
+      None => {
+        Err(format!("Reply target '{}' does not exist.", reply))
+      },
+      Some(target) if target >= height => {
+        Err(format!("Reply target '{}' is not in an earlier block.", reply))
+      },
+      Some(_) => Ok(()),
+    }
+  }
+
   /**
    * Retrieve the latest block.
    */
   pub fn top_block(&self) -> Block {
     self.store.top_block().unwrap()
   }
+}
 
-  /**
-   * Print the chain to stdout.
-   */
-  pub fn print_chain(&self) {
-    println!("==================================================================================");
-    for block in self.chain_iter() {
-      let json = serde_json::to_string_pretty(&block)
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::testing::Account;
+    use crate::blockchain::genesis::GenesisAccount;
+    use crate::blockchain::page::PageRequest;
+
+    #[test]
+    fn test_reply_to_earlier_post_is_accepted() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+      let post = alice.post(&mut chain, "hello", None).unwrap();
+      let reply = alice.post(&mut chain, "hello to you", Some(&post.hash)).unwrap();
 
-      println!("{}", json);
-      println!("==================================================================================");
+      assert_eq!(chain.index.get_replies(&post.hash).unwrap()[0].hash, reply.hash);
     }
-  }
 
-  pub fn chain_iter(&self) -> impl Iterator<Item = Block> + '_ {
-    let height = self.store
-      .get_height()
-      .unwrap();
+    #[test]
+    fn test_reply_to_missing_or_malformed_post_is_rejected() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
 
-    (0..=height).map(move |i| {
-      self.store.get_block(i)
-        .unwrap()
-        .unwrap()
-    })
-  }
+      alice.register(&mut chain, "alice");
+
+      let missing = "ab".repeat(32);
+      let err = alice.post(&mut chain, "hello?", Some(&missing)).unwrap_err();
+      assert_eq!(err, format!("Reply target '{}' does not exist.", missing));
+
+      let err = alice.post(&mut chain, "hello?", Some("not a hash")).unwrap_err();
+      assert_eq!(err, "Reply 'not a hash' is not a post hash.");
+
+      assert_eq!(chain.len(), 1);
+    }
+
+    #[test]
+    fn test_mempool_is_kept_between_runs() {
+      let dir = crate::blockchain::temp_dir();
+      let genesis = Genesis::profile("devnet").unwrap();
+      let alice = Account::new(1);
+
+      let mut chain = Blockchain::open(&dir, &genesis).unwrap();
+      alice.register(&mut chain, "alice");
+      chain.push_mempool(alice.pending(BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      })).unwrap();
+      chain.close().unwrap();
+
+      let mut chain = Blockchain::open(&dir, &genesis).unwrap();
+      assert_eq!(chain.mpool.len(), 1);
+      assert!(!dir.join("mempool.json").exists());
+
+      let reader = chain.reader();
+      assert_eq!(reader.mempool_size(), 1);
+
+      let pending = chain.pop_mempool().unwrap();
+      assert_eq!(reader.mempool_size(), 0);
+
+      chain.requeue_mempool(pending);
+      assert_eq!(reader.mempool_size(), 1);
+    }
+
+    #[test]
+    fn test_mempool_rejects_reply_to_missing_post() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+
+      let missing = "ab".repeat(32);
+      let pending = alice.pending(BlockData::Post {
+        body:  "hello?".to_string(),
+        reply: Some(missing.clone()),
+      });
+
+      assert_eq!(
+        chain.push_mempool(pending).unwrap_err(),
+        format!("Reply target '{}' does not exist.", missing)
+      );
+      assert!(chain.mpool.is_empty());
+    }
+
+    #[test]
+    fn test_update_requires_registered_key() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      let update = BlockData::UserUpdate {
+        display_name: "Alice".to_string(),
+        biography:    "".to_string(),
+      };
+      let expected = format!("Public key '{}' is not registered.", alice.public_key());
+
+      assert_eq!(chain.push_mempool(alice.pending(update.clone())).unwrap_err(), expected);
+      assert_eq!(chain.add_block(alice.mine(&chain, update)).unwrap_err(), expected);
+      assert_eq!(chain.len(), 0);
+    }
+
+    #[test]
+    fn test_update_has_registration_limits() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+
+      let update = BlockData::UserUpdate {
+        display_name: "Alice".to_string(),
+        biography:    "a".repeat(301),
+      };
+
+      assert_eq!(
+        chain.add_block(alice.mine(&chain, update)).unwrap_err(),
+        "Biography exceeds 300 characters."
+      );
+      assert_eq!(chain.len(), 1);
+    }
+
+    #[test]
+    fn test_usernames_are_unique_ignoring_case() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+      let bob = Account::new(2);
+
+      alice.register(&mut chain, "alice");
+
+      let register = |username: &str| bob.pending(BlockData::User {
+        display_name: "Bob".to_string(),
+        username:     username.to_string(),
+        biography:    "".to_string(),
+      });
+
+      assert_eq!(
+        chain.push_mempool(register("ALICE")).unwrap_err(),
+        "Username 'ALICE' is already taken."
+      );
+      assert_eq!(
+        chain.push_mempool(register("bob smith")).unwrap_err(),
+        "Username must be 3 to 30 letters, digits or underscores."
+      );
+      assert!(chain.push_mempool(register("Bob")).is_ok());
+    }
+
+    #[test]
+    fn test_posts_are_rate_limited() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+
+      let post = BlockData::Post {
+        body:  "spam".to_string(),
+        reply: None,
+      };
+
+      for i in 0..POST_LIMIT {
+        let mut block = Block::next(&chain.top_block(), post.clone());
+        block.public_key = alice.public_key();
+        assert_eq!(chain.difficulty(&block).unwrap(), 3 + i / 4);
+
+        alice.post(&mut chain, "spam", None).unwrap();
+      }
+
+      let expected = format!("Public key '{}' has posted too much recently.", alice.public_key());
+      assert_eq!(alice.post(&mut chain, "spam", None).unwrap_err(), expected);
+      assert_eq!(chain.push_mempool(alice.pending(post)).unwrap_err(), expected);
+    }
+
+    #[test]
+    fn test_genesis_cannot_be_replaced() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+      let genesis = chain.genesis_hash();
+
+      let post = BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      };
+
+      let mut block = Block::new(post.clone(), 0, "0".to_string());
+      block.public_key = alice.public_key();
+      block.signature  = alice.sign(&post);
+      block.mine_block(post.rules().difficulty);
+
+      assert!(chain.add_block(block).is_err());
+      assert_eq!(chain.genesis_hash(), genesis);
+    }
+
+    #[test]
+    fn test_past_index_is_rejected() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+      for _ in 0..POST_LIMIT {
+        alice.post(&mut chain, "spam", None).unwrap();
+      }
+
+      // A post at the height of the registration sees none of the posts
+      // after it, so it would get past the rate limit and the extra work.
+      let post = BlockData::Post {
+        body:  "spam".to_string(),
+        reply: None,
+      };
+
+      let mut block = Block::next(&chain.top_block(), post.clone());
+      block.index = 1;
+      block.public_key = alice.public_key();
+      block.signature  = alice.sign(&post);
+      block.mine_block(chain.difficulty(&block).unwrap());
+
+      let height = chain.len();
+      assert_eq!(
+        chain.add_block(block).unwrap_err(),
+        format!("Block index 1 does not follow the chain height {}.", height),
+      );
+      assert!(matches!(chain.at(1).unwrap().data, BlockData::User { .. }));
+      assert_eq!(chain.len(), height);
+    }
+
+    #[test]
+    fn test_mempool_counts_pending_posts() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+
+      for i in 0..POST_LIMIT {
+        chain.push_mempool(alice.pending(BlockData::Post {
+          body:  format!("spam {}", i),
+          reply: None,
+        })).unwrap();
+      }
+
+      assert!(chain.push_mempool(alice.pending(BlockData::Post {
+        body:  "one more".to_string(),
+        reply: None,
+      })).is_err());
+    }
+
+    #[test]
+    fn test_underworked_post_is_rejected() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+
+      let data = BlockData::Post {
+        body:  "a".repeat(250),
+        reply: None,
+      };
+
+      let mut block = Block::next(&chain.top_block(), data.clone());
+      block.public_key = alice.public_key();
+      block.signature  = alice.sign(&data);
+      block.mine_block(3);
+
+      // A base difficulty hash can meet the long post difficulty by chance.
+      if !block.hash.starts_with("0000") {
+        assert_eq!(chain.add_block(block).unwrap_err(), "Block hash did not meet difficulty.");
+      }
+    }
+
+    #[test]
+    fn test_forged_hash_is_rejected() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      let mut block = alice.mine(&chain, BlockData::User {
+        display_name: "alice".to_string(),
+        username:     "alice".to_string(),
+        biography:    "".to_string(),
+      });
+      block.hash = format!("{}{}", "0".repeat(8), &block.hash[8..]);
+
+      assert_eq!(chain.add_block(block).unwrap_err(), "Block hash does not match its contents.");
+      assert_eq!(chain.len(), 0);
+    }
+
+    #[test]
+    fn test_chain_keeps_its_genesis() {
+      let dir = crate::blockchain::temp_dir();
+      let devnet = Genesis::profile("devnet").unwrap();
+
+      let genesis = Blockchain::open(&dir, &devnet).unwrap().genesis_hash();
+      assert_eq!(genesis, devnet.block().hash);
+
+      // Opening it again doesn't replace the genesis block.
+      assert_eq!(Blockchain::open(&dir, &devnet).unwrap().genesis_hash(), genesis);
+
+      let err = Blockchain::open(&dir, &Genesis::profile("testnet").unwrap()).unwrap_err();
+      assert!(err.ends_with("was not started from the testnet genesis block."));
+    }
+
+    #[test]
+    fn test_genesis_accounts_are_registered() {
+      let alice = Account::new(1);
+
+      let mut genesis = Genesis::profile("devnet").unwrap();
+      genesis.accounts.push(GenesisAccount {
+        public_key:   alice.public_key(),
+        username:     "alice".to_string(),
+        display_name: "Alice".to_string(),
+        biography:    "".to_string(),
+      });
+
+      let mut chain = Blockchain::open(&crate::blockchain::temp_dir(), &genesis).unwrap();
+
+      assert!(chain.index.has_username("alice").unwrap());
+      assert!(alice.post(&mut chain, "hello", None).is_ok());
+    }
+
+    #[test]
+    fn test_genesis_is_only_the_first_block() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      assert_eq!(
+        chain.add_block(alice.mine(&chain, BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] })).unwrap_err(),
+        "Only the first block can be a genesis block."
+      );
+    }
+
+    #[test]
+    fn test_rollback_reverts_state_and_index() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+      let post = alice.post(&mut chain, "hello #world", None).unwrap();
+
+      let update = BlockData::UserUpdate {
+        display_name: "Alice".to_string(),
+        biography:    "Hi!".to_string(),
+      };
+      chain.add_block(alice.mine(&chain, update)).unwrap();
+
+      chain.rollback(1).unwrap();
+
+      assert_eq!(chain.len(), 1);
+      assert_eq!(chain.index.height().unwrap(), Some(1));
+      assert!(chain.index.get_post(&post.hash).unwrap().is_none());
+      assert!(chain.index.get_hashtag_posts("world", &PageRequest::first(10)).unwrap().items.is_empty());
+      assert_eq!(chain.index.get_user_by_username("alice").unwrap().unwrap().display_name, "alice");
+
+      chain.rollback(0).unwrap();
+
+      assert!(chain.store.get_account(&alice.public_key()).unwrap().is_none());
+      assert!(chain.store.get_username_owner("alice").unwrap().is_none());
+      assert!(!chain.index.has_username("alice").unwrap());
+    }
+
+    #[test]
+    fn test_index_catches_up_with_the_next_block() {
+      let mut chain = Blockchain::temp();
+      let alice = Account::new(1);
+
+      alice.register(&mut chain, "alice");
+      let first = alice.post(&mut chain, "first", None).unwrap();
+
+      // The index missed the post.
+      chain.index.remove_block(&first).unwrap();
+
+      let second = alice.post(&mut chain, "second", None).unwrap();
+
+      assert_eq!(chain.index.height().unwrap(), Some(3));
+      assert!(chain.index.get_post(&first.hash).unwrap().is_some());
+      assert!(chain.index.get_post(&second.hash).unwrap().is_some());
+    }
+
+    /**
+     * Adding a block should cost the same no matter how long the chain is.
+     * Run with `cargo test bench_ -- --ignored --nocapture`.
+     */
+    #[test]
+    #[ignore]
+    fn bench_block_acceptance() {
+      let mut chain = Blockchain::temp();
+
+      // Enough accounts that none of them runs into the anti-spam policy.
+      let accounts: Vec<Account> = (1..=40).map(Account::new).collect();
+      for (i, account) in accounts.iter().enumerate() {
+        account.register(&mut chain, &format!("user{}", i));
+      }
+
+      let mut timings = vec![];
+
+      for i in 0..1000 {
+        let block = accounts[i % accounts.len()].mine(&chain, BlockData::Post {
+          body:  format!("post {}", i),
+          reply: None,
+        });
+
+        let start = std::time::Instant::now();
+        chain.add_block(block).unwrap();
+        timings.push(start.elapsed());
+      }
+
+      let average = |t: &[std::time::Duration]| t.iter().sum::<std::time::Duration>() / t.len() as u32;
+      let first = average(&timings[..100]);
+      let last  = average(&timings[900..]);
+
+      println!("add_block: {:?} at heights 41-140, {:?} at heights 941-1040", first, last);
+
+      assert!(last < first * 3);
+    }
 }
@@ -0,0 +1,129 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use crate::blockchain::block::{Block, BlockData};
+use crate::blockchain::rules::is_username;
+
+/**
+ * An account registered by the genesis block.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisAccount {
+  pub public_key:   String,
+  pub username:     String,
+  #[serde(default)]
+  pub display_name: String,
+  #[serde(default)]
+  pub biography:    String,
+}
+
+/**
+ * The definition of a network. Every field goes into the genesis block, so
+ * nodes only agree on a chain when they are started with the same genesis.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Genesis {
+  pub network:   String,
+  pub timestamp: u64,
+  #[serde(default)]
+  pub accounts:  Vec<GenesisAccount>,
+}
+
+impl Genesis {
+  /**
+   * The built-in networks.
+   */
+  pub fn profile(network: &str) -> Option<Self> {
+    let timestamp = match network {
+      "mainnet" => 1735689600, // 2025-01-01
+      "testnet" => 1738368000, // 2025-02-01
+      "devnet"  => 1740787200, // 2025-03-01
+      _         => return None,
+    };
+
+    Some(Self {
+      network: network.to_string(),
+      timestamp,
+      accounts: vec![],
+    })
+  }
+
+  /**
+   * Validate the accounts. They skip the consensus rules for registrations,
+   * so they are held to them here.
+   */
+  pub fn validate(&self) -> Result<(), String> {
+    let mut usernames = HashSet::new();
+    let mut keys = HashSet::new();
+
+    for account in &self.accounts {
+      if !is_username(&account.username) {
+        return Err(format!("Genesis username '{}' is not valid.", account.username));
+      }
+
+      if !usernames.insert(account.username.to_lowercase()) {
+        return Err(format!("Genesis username '{}' is registered twice.", account.username));
+      }
+
+      let key_valid = hex::decode(&account.public_key)
+        .is_ok_and(|key| key.len() == 32);
+
+      if !key_valid {
+        return Err(format!("Genesis public key '{}' is not valid.", account.public_key));
+      }
+
+      if !keys.insert(account.public_key.clone()) {
+        return Err(format!("Genesis public key '{}' is registered twice.", account.public_key));
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Build the genesis block.
+   */
+  pub fn block(&self) -> Block {
+    let mut block = Block::new(BlockData::Genesis {
+      network:  self.network.clone(),
+      accounts: self.accounts.clone(),
+    }, 0, "0".to_string());
+
+    block.timestamp = self.timestamp;
+    block.hash      = block.hash_block();
+    block
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genesis_block_is_fixed() {
+      let mainnet = Genesis::profile("mainnet").unwrap();
+
+      assert_eq!(mainnet.block().hash, mainnet.block().hash);
+      assert_ne!(mainnet.block().hash, Genesis::profile("testnet").unwrap().block().hash);
+      assert!(Genesis::profile("moonnet").is_none());
+    }
+
+    #[test]
+    fn test_accounts_are_validated() {
+      let account = |public_key: &str, username: &str| GenesisAccount {
+        public_key:   public_key.to_string(),
+        username:     username.to_string(),
+        display_name: String::new(),
+        biography:    String::new(),
+      };
+
+      let mut genesis = Genesis::profile("devnet").unwrap();
+      genesis.accounts = vec![account(&"ab".repeat(32), "alice"), account(&"cd".repeat(32), "bob")];
+      assert!(genesis.validate().is_ok());
+
+      genesis.accounts.push(account(&"ef".repeat(32), "ALICE"));
+      assert_eq!(genesis.validate().unwrap_err(), "Genesis username 'ALICE' is registered twice.");
+
+      genesis.accounts = vec![account("nope", "alice")];
+      assert_eq!(genesis.validate().unwrap_err(), "Genesis public key 'nope' is not valid.");
+    }
+}
@@ -0,0 +1,151 @@
+use std::thread;
+use tokio::sync::{mpsc, oneshot, watch};
+use crate::blockchain::chain::Blockchain;
+use crate::blockchain::reader::ChainReader;
+
+enum Job {
+  Run(Box<dyn FnOnce(&mut Blockchain) + Send>),
+  // Close the chain and stop the writer.
+  Close(oneshot::Sender<Result<(), String>>),
+}
+
+/**
+ * A shared handle to the chain. Everything that changes the chain, adding
+ * blocks and filling the memory pool, runs one job at a time on a writer
+ * thread that owns the `Blockchain`. Reads go through the `ChainReader` and
+ * never wait for the writer.
+ */
+#[derive(Debug, Clone)]
+pub struct ChainHandle {
+  jobs:   mpsc::UnboundedSender<Job>,
+  reader: ChainReader,
+  tip:    watch::Receiver<String>,
+}
+
+impl ChainHandle {
+  /**
+   * Move the chain to its writer thread.
+   */
+  pub fn start(mut chain: Blockchain) -> Self {
+    let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+    let reader = chain.reader();
+    let tip = chain.subscribe_tip();
+
+    thread::Builder::new()
+      .name("chain-writer".to_string())
+      .spawn(move || {
+        while let Some(job) = queue.blocking_recv() {
+          match job {
+            Job::Run(job) => job(&mut chain),
+            Job::Close(done) => {
+              let _ = done.send(chain.close());
+              return;
+            },
+          }
+        }
+      })
+      .unwrap();
+
+    Self {
+      jobs,
+      reader,
+      tip,
+    }
+  }
+
+  /**
+   * Run a job on the writer thread and wait for its result.
+   */
+  pub async fn write<T, F>(&self, job: F) -> T
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut Blockchain) -> T + Send + 'static,
+  {
+    let (tx, rx) = oneshot::channel();
+
+    self.jobs
+      .send(Job::Run(Box::new(move |chain| {
+        let _ = tx.send(job(chain));
+      })))
+      .expect("The chain writer has stopped.");
+
+    rx.await.expect("The chain writer has stopped.")
+  }
+
+  /**
+   * Close the chain once the jobs before it have run. Writes after it panic,
+   * so it is the last thing done with the chain.
+   */
+  pub async fn close(&self) -> Result<(), String> {
+    let (tx, rx) = oneshot::channel();
+
+    self.jobs
+      .send(Job::Close(tx))
+      .map_err(|_| "The chain writer has stopped.")?;
+
+    rx.await.map_err(|_| "The chain writer has stopped.")?
+  }
+
+  /**
+   * Read access to the chain.
+   */
+  pub fn reader(&self) -> &ChainReader {
+    &self.reader
+  }
+
+  /**
+   * Watch the hash of the top block.
+   */
+  pub fn subscribe_tip(&self) -> watch::Receiver<String> {
+    self.tip.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc as std_mpsc;
+    use crate::blockchain::block::BlockData;
+    use crate::blockchain::testing::Account;
+
+    #[tokio::test]
+    async fn test_reads_do_not_wait_for_the_writer() {
+      let chain = ChainHandle::start(Blockchain::temp());
+      let (release, blocked) = std_mpsc::channel::<()>();
+
+      // Keep the writer busy until the reads are done.
+      let busy = tokio::spawn({
+        let chain = chain.clone();
+        async move { chain.write(move |_| blocked.recv().unwrap()).await }
+      });
+
+      let index = chain.reader().index().unwrap();
+      assert_eq!(index.height().unwrap(), Some(0));
+      assert_eq!(chain.reader().height(), 0);
+      drop(index);
+
+      release.send(()).unwrap();
+      busy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_readers_see_written_blocks() {
+      let chain = ChainHandle::start(Blockchain::temp());
+      let alice = Account::new(1);
+
+      let block = chain.write(move |chain| {
+        alice.mine(chain, BlockData::User {
+          display_name: "alice".to_string(),
+          username:     "alice".to_string(),
+          biography:    "".to_string(),
+        })
+      }).await;
+
+      let added = block.clone();
+      chain.write(move |chain| chain.add_block(added)).await.unwrap();
+
+      assert_eq!(chain.reader().top_block().hash, block.hash);
+      assert!(chain.reader().index().unwrap().has_username("alice").unwrap());
+      assert_eq!(*chain.subscribe_tip().borrow(), block.hash);
+    }
+}
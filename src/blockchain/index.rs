@@ -1,8 +1,17 @@
 use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use rusqlite::config::DbConfig;
 use rusqlite::OptionalExtension;
-use rusqlite::{params, Connection, Result};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, Result, Row};
+use tracing::{info, warn};
+use crate::metrics::metrics;
 use crate::blockchain::block::Block;
 use crate::blockchain::block::BlockData;
+use crate::blockchain::text;
+use crate::blockchain::page::{Cursor, Page, PageRequest};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Post {
@@ -11,6 +20,7 @@ pub struct Post {
   pub body:      String,
   pub reply:     Option<String>,
   pub timestamp: u64,
+  pub height:    u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -21,6 +31,26 @@ pub struct User {
   pub public_key:   String,
 }
 
+/**
+ * A full-text search over posts. The query supports "quoted phrases" and
+ * prefix* terms, every term has to match.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PostSearch {
+  pub query:  String,
+  pub author: Option<String>,
+  pub since:  Option<u64>,
+  pub until:  Option<u64>,
+  pub page:   PageRequest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trend {
+  pub tag:     String,
+  pub posts:   u64,
+  pub authors: u64,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct PostDetail {
   post:     Post,
@@ -28,16 +58,58 @@ pub struct PostDetail {
   reply_to: Option<Post>,
 }
 
-#[derive(Debug)]
-pub struct Index {
-  sqlite: Connection,
+/**
+ * A conversation around a post: every post it replies to up to the root of
+ * the thread, and a tree of the replies under it.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct Thread {
+  pub ancestors: Vec<ThreadPost>,
+  pub post:      ThreadNode,
 }
 
-impl Index {
-  pub fn new() -> Self {
-    let sqlite = Connection::open("chainindex.db").unwrap();
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadPost {
+  pub post:        Post,
+  pub reply_count: u64,
+}
 
-    let _ = sqlite.execute("
+/**
+ * A post in a reply tree. Replies are oldest first and cut off after the
+ * requested number per post. When a post has more replies, `next` is passed
+ * as `after` to the thread of that post to continue with them.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadNode {
+  pub post:        Post,
+  pub reply_count: u64,
+  pub replies:     Vec<ThreadNode>,
+  pub next:        Option<String>,
+}
+
+// The most posts read for one reply tree, no matter the depth and width.
+const MAX_THREAD_POSTS: usize = 2000;
+
+/**
+ * A step in the index schema history. Migrations are applied in order and the
+ * schema version is the number of migrations that have been applied.
+ */
+struct Migration {
+  description: &'static str,
+  sql:         &'static str,
+  // The migration changes how blocks are indexed, so rows that are already in
+  // the index can't be carried over. The index is dropped and rebuilt from the
+  // chain instead.
+  rebuild:     bool,
+}
+
+const MIGRATIONS: &[Migration] = &[
+  // Indexes created before schema versioning already match this migration,
+  // so it is written to be re-runnable over them.
+  Migration {
+    description: "create posts and users",
+    rebuild:     false,
+    sql: "
       CREATE TABLE IF NOT EXISTS posts (
         hash      TEXT PRIMARY KEY,
         author    TEXT NOT NULL,
@@ -45,30 +117,356 @@ impl Index {
         reply     TEXT,
         timestamp INTEGER NOT NULL
       );
-    ", []);
 
-    let _ = sqlite.execute("CREATE INDEX IF NOT EXISTS idx_posts_author ON posts (author)", []);
-    let _ = sqlite.execute("CREATE INDEX IF NOT EXISTS idx_posts_reply ON posts (reply)", []);
+      CREATE INDEX IF NOT EXISTS idx_posts_author ON posts (author);
+      CREATE INDEX IF NOT EXISTS idx_posts_reply ON posts (reply);
 
-    let _ = sqlite.execute("
       CREATE TABLE IF NOT EXISTS users (
         public_key   TEXT PRIMARY KEY,
         username     TEXT NOT NULL,
         display_name TEXT NOT NULL,
         biography    TEXT
       );
-    ", []);
 
-    let _ = sqlite.execute("CREATE INDEX IF NOT EXISTS idx_users_username ON users (username)", []);
+      CREATE INDEX IF NOT EXISTS idx_users_username ON users (username);
+    ",
+  },
+  Migration {
+    description: "track indexed chain height",
+    rebuild:     false,
+    sql: "
+      CREATE TABLE meta (
+        key   TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+      );
+    ",
+  },
+  Migration {
+    description: "full-text search over posts and users",
+    rebuild:     false,
+    sql: "
+      CREATE VIRTUAL TABLE posts_fts USING fts5 (
+        body,
+        content = 'posts',
+        tokenize = 'unicode61 remove_diacritics 2'
+      );
+
+      CREATE TRIGGER posts_fts_insert AFTER INSERT ON posts BEGIN
+        INSERT INTO posts_fts (rowid, body) VALUES (new.rowid, new.body);
+      END;
+
+      CREATE TRIGGER posts_fts_delete AFTER DELETE ON posts BEGIN
+        INSERT INTO posts_fts (posts_fts, rowid, body) VALUES ('delete', old.rowid, old.body);
+      END;
+
+      CREATE TRIGGER posts_fts_update AFTER UPDATE ON posts BEGIN
+        INSERT INTO posts_fts (posts_fts, rowid, body) VALUES ('delete', old.rowid, old.body);
+        INSERT INTO posts_fts (rowid, body) VALUES (new.rowid, new.body);
+      END;
+
+      INSERT INTO posts_fts (posts_fts) VALUES ('rebuild');
+
+      CREATE VIRTUAL TABLE users_fts USING fts5 (
+        username,
+        display_name,
+        biography,
+        content = 'users',
+        tokenize = \"unicode61 remove_diacritics 2 tokenchars '_'\"
+      );
+
+      CREATE TRIGGER users_fts_insert AFTER INSERT ON users BEGIN
+        INSERT INTO users_fts (rowid, username, display_name, biography)
+        VALUES (new.rowid, new.username, new.display_name, new.biography);
+      END;
+
+      CREATE TRIGGER users_fts_delete AFTER DELETE ON users BEGIN
+        INSERT INTO users_fts (users_fts, rowid, username, display_name, biography)
+        VALUES ('delete', old.rowid, old.username, old.display_name, old.biography);
+      END;
+
+      CREATE TRIGGER users_fts_update AFTER UPDATE ON users BEGIN
+        INSERT INTO users_fts (users_fts, rowid, username, display_name, biography)
+        VALUES ('delete', old.rowid, old.username, old.display_name, old.biography);
+        INSERT INTO users_fts (rowid, username, display_name, biography)
+        VALUES (new.rowid, new.username, new.display_name, new.biography);
+      END;
+
+      INSERT INTO users_fts (users_fts) VALUES ('rebuild');
+
+      CREATE INDEX idx_posts_timestamp ON posts (timestamp);
+    ",
+  },
+  // Hashtags and mentions are parsed from post bodies when blocks are
+  // indexed, so the posts that are already indexed have to be parsed again.
+  Migration {
+    description: "hashtags and mentions",
+    rebuild:     true,
+    sql: "
+      CREATE TABLE hashtags (
+        tag       TEXT NOT NULL,
+        post      TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (tag, post)
+      );
+
+      CREATE INDEX idx_hashtags_timestamp ON hashtags (timestamp);
+
+      CREATE TABLE mentions (
+        username  TEXT NOT NULL,
+        post      TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (username, post)
+      );
+    ",
+  },
+  // Feeds are ordered by the height of the block a post is in, which wasn't
+  // indexed before.
+  Migration {
+    description: "post block heights",
+    rebuild:     true,
+    sql: "
+      ALTER TABLE posts ADD COLUMN height INTEGER NOT NULL DEFAULT 0;
+
+      CREATE INDEX idx_posts_height ON posts (height, timestamp, hash);
+    ",
+  },
+  // Earlier chains could register the same username in different cases, so
+  // the index is rebuilt and only the first registration is kept.
+  Migration {
+    description: "case-insensitive unique usernames",
+    rebuild:     true,
+    sql: "
+      DROP INDEX idx_users_username;
+
+      CREATE UNIQUE INDEX idx_users_username ON users (username COLLATE NOCASE);
+    ",
+  },
+];
+
+const POST_COLUMNS: &str = "
+  posts.hash,
+  posts.body,
+  posts.reply,
+  posts.timestamp,
+  posts.height,
+  users.display_name,
+  users.username,
+  users.biography,
+  users.public_key
+";
+
+#[derive(Debug)]
+pub struct Index {
+  sqlite:  Connection,
+  rebuilt: bool,
+}
+
+impl Default for Index {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Index {
+  pub fn new() -> Self {
+    Self::open("chainindex.db").unwrap()
+  }
+
+  /**
+   * Open the index at the given path and bring its schema up to date.
+   */
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let sqlite = Connection::open(path)?;
+
+    // Readers on other connections see the last commit instead of waiting
+    // for the writer.
+    sqlite.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+
+    let mut index = Self {
+      sqlite,
+      rebuilt: false,
+    };
+
+    index.migrate()?;
+
+    Ok(index)
+  }
+
+  /**
+   * Open a read-only connection to an index that the writer has opened and
+   * migrated.
+   */
+  pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let sqlite = Connection::open_with_flags(
+      path,
+      OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    sqlite.busy_timeout(Duration::from_secs(5))?;
+
+    Ok(Self {
+      sqlite,
+      rebuilt: false,
+    })
+  }
+
+  /**
+   * Close the connection, reporting what dropping it would ignore.
+   */
+  pub fn close(self) -> Result<()> {
+    self.sqlite
+      .close()
+      .map_err(|(_, e)| e)
+  }
+
+  /**
+   * The path of the index file, if it isn't in memory.
+   */
+  pub fn path(&self) -> Option<PathBuf> {
+    self.sqlite
+      .path()
+      .filter(|path| !path.is_empty())
+      .map(PathBuf::from)
+  }
+
+  /**
+   * The schema version of the index.
+   */
+  pub fn schema_version(&self) -> Result<usize> {
+    self.sqlite.query_row("SELECT version FROM schema_version", [], |row| {
+      row.get::<_, i64>(0)
+    }).map(|v| v as usize)
+  }
+
+  /**
+   * The schema version the index is migrated to when opened.
+   */
+  pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+  }
+
+  /**
+   * Check if the index was dropped while opening and has to be rebuilt from
+   * the chain.
+   */
+  pub fn was_rebuilt(&self) -> bool {
+    self.rebuilt
+  }
+
+  /**
+   * Apply the pending migrations. If they can't be applied in place, the
+   * index is dropped and created again from the first migration.
+   */
+  fn migrate(&mut self) -> Result<()> {
+    self.sqlite.execute_batch("
+      CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER NOT NULL
+      );
+
+      INSERT INTO schema_version (version)
+      SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM schema_version);
+    ")?;
+
+    let version = self.schema_version()?;
+
+    if version == MIGRATIONS.len() {
+      return Ok(());
+    }
+
+    // A new index has no rows to carry over. Indexes from before schema
+    // versioning are also at version 0, but already have tables.
+    let fresh: bool = self.sqlite.query_row("
+      SELECT COUNT(*) = 0 FROM sqlite_master WHERE name = 'posts'
+    ", [], |row| row.get(0))?;
+
+    // The index was written by a newer version of the node, or one of the
+    // pending migrations can't be applied to the rows that are already there.
+    let in_place = version < MIGRATIONS.len()
+      && (fresh || MIGRATIONS[version..].iter().all(|m| !m.rebuild));
+
+    if in_place {
+      match self.apply_migrations(version) {
+        Ok(()) => return Ok(()),
+        Err(e) => warn!(error = %e, "Index migration failed"),
+      }
+    }
+
+    if version > 0 {
+      info!(version, "Rebuilding the chain index");
+    }
+
+    self.reset()?;
+    self.apply_migrations(0)?;
+    self.rebuilt = true;
+
+    Ok(())
+  }
+
+  /**
+   * Apply the migrations after the given version, one transaction each.
+   */
+  fn apply_migrations(&mut self, from: usize) -> Result<()> {
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+      let tx = self.sqlite.transaction()?;
+      tx.execute_batch(migration.sql)?;
+      tx.execute("UPDATE schema_version SET version = ?1", [i + 1])?;
+      tx.commit()?;
+
+      info!(version = i + 1, description = migration.description, "Applied index migration");
+    }
+    Ok(())
+  }
+
+  /**
+   * Drop everything in the index, leaving an empty index at the latest
+   * schema version.
+   */
+  pub fn clear(&mut self) -> Result<()> {
+    self.reset()?;
+    self.apply_migrations(0)
+  }
+
+  /**
+   * Drop everything in the index.
+   */
+  fn reset(&self) -> Result<()> {
+    self.sqlite.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, true)?;
+    self.sqlite.execute_batch("VACUUM")?;
+    self.sqlite.set_db_config(DbConfig::SQLITE_DBCONFIG_RESET_DATABASE, false)?;
+
+    self.sqlite.execute_batch("
+      CREATE TABLE schema_version (
+        version INTEGER NOT NULL
+      );
+
+      INSERT INTO schema_version (version) VALUES (0);
+    ")
+  }
 
+  /**
+   * The height of the last block added to the index.
+   */
+  pub fn height(&self) -> Result<Option<u64>> {
+    let _timer = metrics().time_query("height");
 
-    Self { sqlite }
+    self.sqlite.query_row("SELECT value FROM meta WHERE key = 'height'", [], |row| {
+      row.get::<_, i64>(0)
+    }).optional().map(|h| h.map(|h| h as u64))
   }
 
   /**
    * Add a block to the index.
    */
   pub fn add_block(&self, block: Block) -> Result<(), rusqlite::Error> {
+    let _timer = metrics().time_query("add_block");
+
+    let tx = self.sqlite.unchecked_transaction()?;
+
+    tx.execute("
+      INSERT INTO meta (key, value) VALUES ('height', ?1)
+      ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value)
+    ", [block.index])?;
+
     match block.clone().data {
       BlockData::Post {..} => {
         self.index_post(block)?;
@@ -79,8 +477,63 @@ impl Index {
       BlockData::UserUpdate { .. } => {
         self.index_user(block)?;
       },
+      BlockData::Genesis { accounts, .. } => {
+        for account in accounts {
+          self.sqlite.execute("
+            INSERT OR IGNORE INTO users
+            (public_key, username, display_name, biography) VALUES
+            (?1, ?2, ?3, ?4)
+          ", params![
+            account.public_key,
+            account.username,
+            account.display_name,
+            account.biography,
+          ])?;
+        }
+      },
+    }
+
+    tx.commit()
+  }
+
+  /**
+   * Remove a block that was rolled back from the index.
+   */
+  pub fn remove_block(&self, block: &Block) -> Result<(), rusqlite::Error> {
+    let _timer = metrics().time_query("remove_block");
+
+    let tx = self.sqlite.unchecked_transaction()?;
+
+    tx.execute("
+      UPDATE meta SET value = ?1 WHERE key = 'height'
+    ", [block.index.saturating_sub(1)])?;
+
+    match &block.data {
+      BlockData::Post { .. } => {
+        tx.execute("DELETE FROM posts WHERE hash = ?1", [&block.hash])?;
+        tx.execute("DELETE FROM hashtags WHERE post = ?1", [&block.hash])?;
+        tx.execute("DELETE FROM mentions WHERE post = ?1", [&block.hash])?;
+      },
+      BlockData::User { .. } => {
+        tx.execute("DELETE FROM users WHERE public_key = ?1", [&block.public_key])?;
+      },
       _ => {}
     }
+
+    tx.commit()
+  }
+
+  /**
+   * Set the profile of a user.
+   */
+  pub fn set_profile(&self, public_key: &str, display_name: &str, biography: &str) -> Result<(), rusqlite::Error> {
+    let _timer = metrics().time_query("set_profile");
+
+    self.sqlite.execute("
+      UPDATE users
+      SET display_name = ?1, biography = ?2
+      WHERE public_key = ?3
+    ", params![display_name, biography, public_key])?;
     Ok(())
   }
 
@@ -88,15 +541,32 @@ impl Index {
     if let BlockData::Post { body, reply, .. } = block.clone().data {
       self.sqlite.execute("
         INSERT OR IGNORE INTO posts
-        (hash, author, body, reply, timestamp) VALUES
-        (?1, ?2, ?3, ?4, ?5)
+        (hash, author, body, reply, timestamp, height) VALUES
+        (?1, ?2, ?3, ?4, ?5, ?6)
       ", params![
         block.clone().hash,
         block.clone().public_key,
         body,
         reply,
         block.clone().timestamp,
+        block.index,
       ])?;
+
+      for tag in text::hashtags(&body) {
+        self.sqlite.execute("
+          INSERT OR IGNORE INTO hashtags
+          (tag, post, timestamp) VALUES
+          (?1, ?2, ?3)
+        ", params![tag, block.hash, block.timestamp])?;
+      }
+
+      for username in text::mentions(&body) {
+        self.sqlite.execute("
+          INSERT OR IGNORE INTO mentions
+          (username, post, timestamp) VALUES
+          (?1, ?2, ?3)
+        ", params![username, block.hash, block.timestamp])?;
+      }
     }
     Ok(())
   }
@@ -140,97 +610,51 @@ impl Index {
   }
 
   /**
-   * Retrieve a feed for a set of users.
+   * Retrieve a feed for a set of users, newest first.
    */
-  pub fn get_feed(&self, users: Vec<String>, limit: usize, offset: usize) -> Result<Vec<Post>> {
+  pub fn get_feed(&self, users: Vec<String>, page: &PageRequest) -> Result<Page<Post>> {
+    let _timer = metrics().time_query("get_feed");
+
     let placeholders = users
         .iter()
         .map(|_| "?".to_string())
         .collect::<Vec<_>>()
         .join(", ");
 
-    let query = format!("
-      SELECT
-        posts.hash,
-        posts.body,
-        posts.reply,
-        posts.timestamp,
-        users.display_name,
-        users.username,
-        users.biography,
-        users.public_key
+    let from = format!("
       FROM posts
       JOIN users ON users.public_key = posts.author
       WHERE users.username IN ({})
-      LIMIT ?
-      OFFSET ?
     ", placeholders);
 
-    let mut params: Vec<&dyn rusqlite::ToSql> = users
-      .iter()
-      .map(|u| u as &dyn rusqlite::ToSql)
+    let params = users
+      .into_iter()
+      .map(Value::from)
       .collect();
 
-    params.push(&limit);
-    params.push(&offset);
-
-    let posts = self.sqlite
-      .prepare(&query)?
-      .query_map(params.as_slice(), |row| {
-        Ok(Post {
-          author:    User {
-            display_name: row.get("display_name")?,
-            username:     row.get("username")?,
-            biography:    row.get("biography")?,
-            public_key:   row.get("public_key")?,
-          },
-          hash:      row.get("hash")?,
-          body:      row.get("body")?,
-          reply:     row.get::<_, Option<String>>("reply")?,
-          timestamp: row.get::<_, i64>("timestamp")? as u64,
-        })
-      })?
-      .collect::<Result<Vec<Post>, _>>()?;
-    Ok(posts)
+    self.paginate_posts(&from, params, page)
   }
 
   /**
    * Retrieve a post by its hash.
    */
   pub fn get_post(&self, hash: &str) -> Result<Option<Post>> {
-    self.sqlite.query_row("
-      SELECT
-        posts.hash,
-        posts.body,
-        posts.reply,
-        posts.timestamp,
-        users.display_name,
-        users.username,
-        users.biography,
-        users.public_key
+    let _timer = metrics().time_query("get_post");
+
+    self.sqlite.query_row(&format!("
+      SELECT {}
       FROM posts
       JOIN users ON users.public_key = posts.author
       WHERE posts.hash = ?1
-    ", [hash], |row| {
-      Ok(Post {
-        author:    User {
-          display_name: row.get("display_name")?,
-          username:     row.get("username")?,
-          biography:    row.get("biography")?,
-          public_key:   row.get("public_key")?,
-        },
-        hash:      row.get("hash")?,
-        body:      row.get("body")?,
-        reply:     row.get::<_, Option<String>>("reply")?,
-        timestamp: row.get::<_, i64>("timestamp")? as u64,
-      })
-    }).optional()
+    ", POST_COLUMNS), [hash], map_post).optional()
   }
 
   /**
    * Hydrate a post with full detail.
    */
   pub fn hydrate_post(&self, post: Post) -> Result<PostDetail> {
+    let _timer = metrics().time_query("hydrate_post");
+
     let replies = self.get_replies(&post.hash)?;
     let reply_to = post.clone().reply
         .map(|r| self.get_post(&r))
@@ -251,44 +675,215 @@ impl Index {
       .collect()
   }
 
+  /**
+   * Retrieve the height of the block a post is in.
+   */
+  pub fn post_height(&self, hash: &str) -> Result<Option<u64>> {
+    let _timer = metrics().time_query("post_height");
+
+    self.sqlite.query_row("
+      SELECT height FROM posts WHERE hash = ?1
+    ", [hash], |row| row.get::<_, i64>(0))
+      .optional()
+      .map(|h| h.map(|h| h as u64))
+  }
+
+  /**
+   * Retrieve the thread around a post. The reply tree goes `depth` levels
+   * down and has at most `page.limit` replies per post. `page.after` skips the
+   * direct replies to the post up to and including the cursor.
+   */
+  pub fn get_thread(&self, hash: &str, depth: usize, page: &PageRequest) -> Result<Option<Thread>> {
+    let _timer = metrics().time_query("get_thread");
+
+    let Some(post) = self.get_post(hash)? else {
+      return Ok(None);
+    };
+
+    let ancestors = self.sqlite
+      .prepare(&format!("
+        WITH RECURSIVE ancestors (hash, reply, depth) AS (
+          SELECT hash, reply, 0 FROM posts WHERE hash = ?1
+          UNION ALL
+          SELECT posts.hash, posts.reply, ancestors.depth + 1
+          FROM posts
+          JOIN ancestors ON posts.hash = ancestors.reply
+          WHERE ancestors.depth < 10000
+        )
+        SELECT
+          {},
+          (SELECT COUNT(*) FROM posts AS r WHERE r.reply = posts.hash) AS reply_count
+        FROM ancestors
+        JOIN posts ON posts.hash = ancestors.hash
+        JOIN users ON users.public_key = posts.author
+        WHERE ancestors.depth > 0
+        ORDER BY ancestors.depth DESC
+      ", POST_COLUMNS))?
+      .query_map([hash], |row| {
+        Ok(ThreadPost {
+          post:        map_post(row)?,
+          reply_count: row.get::<_, i64>("reply_count")? as u64,
+        })
+      })?
+      .collect::<Result<Vec<ThreadPost>, _>>()?;
+
+    let (height, timestamp, after) = match &page.after {
+      Some(Cursor::Chain { height, timestamp, hash }) => (*height as i64, *timestamp as i64, hash.clone()),
+      _ => (-1, -1, String::new()),
+    };
+
+    // The tree is read breadth first so that the cap on the number of posts
+    // cuts off the deepest replies. Each post only brings in the first replies
+    // up to the limit, plus one to see if there are more, so the cap is spent
+    // on replies that make it into the tree.
+    let mut stmt = self.sqlite.prepare(&format!("
+      WITH RECURSIVE descendants (hash, depth) AS (
+        SELECT hash, 1 FROM (
+          SELECT hash FROM posts
+          WHERE reply = ?1
+            AND (height, timestamp, hash) > (?2, ?3, ?4)
+          ORDER BY height, timestamp, hash
+          LIMIT ?7
+        )
+        UNION ALL
+        SELECT posts.hash, descendants.depth + 1
+        FROM descendants
+        JOIN posts ON posts.hash IN (
+          SELECT siblings.hash FROM posts AS siblings
+          WHERE siblings.reply = descendants.hash
+          ORDER BY siblings.height, siblings.timestamp, siblings.hash
+          LIMIT ?7
+        )
+        WHERE descendants.depth < ?5
+        ORDER BY 2
+        LIMIT ?6
+      )
+      SELECT
+        {},
+        (SELECT COUNT(*) FROM posts AS r WHERE r.reply = posts.hash) AS reply_count
+      FROM descendants
+      JOIN posts ON posts.hash = descendants.hash
+      JOIN users ON users.public_key = posts.author
+      ORDER BY posts.height, posts.timestamp, posts.hash
+    ", POST_COLUMNS))?;
+
+    let rows = stmt
+      .query_map(params![
+        hash,
+        height,
+        timestamp,
+        after,
+        depth,
+        MAX_THREAD_POSTS,
+        page.limit + 1,
+      ], |row| {
+        Ok(ThreadPost {
+          post:        map_post(row)?,
+          reply_count: row.get::<_, i64>("reply_count")? as u64,
+        })
+      })?
+      .collect::<Result<Vec<ThreadPost>, _>>()?;
+
+    let mut children: HashMap<String, Vec<ThreadPost>> = HashMap::new();
+    for row in rows {
+      if let Some(parent) = row.post.reply.clone() {
+        children.entry(parent).or_default().push(row);
+      }
+    }
+
+    let reply_count = self.sqlite.query_row("
+      SELECT COUNT(*) FROM posts WHERE reply = ?1
+    ", [hash], |row| row.get::<_, i64>(0))? as u64;
+
+    Ok(Some(Thread {
+      ancestors,
+      post: build_thread_node(ThreadPost { post, reply_count }, &mut children, page.limit),
+    }))
+  }
+
+  /**
+   * Retrieve the replies to a post, oldest first.
+   */
   pub fn get_replies(&self, hash: &str) -> Result<Vec<Post>> {
+    let _timer = metrics().time_query("get_replies");
+
     let posts = self.sqlite
-      .prepare("
-        SELECT
-          posts.hash,
-          posts.body,
-          posts.reply,
-          posts.timestamp,
-          users.display_name,
-          users.username,
-          users.biography,
-          users.public_key
+      .prepare(&format!("
+        SELECT {}
         FROM posts
         JOIN users ON users.public_key = posts.author
         WHERE posts.reply = ?1
+        ORDER BY posts.height, posts.timestamp, posts.hash
+      ", POST_COLUMNS))?
+      .query_map([hash], map_post)?
+      .collect::<Result<Vec<Post>, _>>()?;
+    Ok(posts)
+  }
+
+  /**
+   * Retrieve the posts tagged with a hashtag, newest first.
+   */
+  pub fn get_hashtag_posts(&self, tag: &str, page: &PageRequest) -> Result<Page<Post>> {
+    let _timer = metrics().time_query("get_hashtag_posts");
+
+    self.paginate_posts("
+      FROM hashtags
+      JOIN posts ON posts.hash = hashtags.post
+      JOIN users ON users.public_key = posts.author
+      WHERE hashtags.tag = lower(?)
+    ", vec![Value::from(tag.to_string())], page)
+  }
+
+  /**
+   * Retrieve the posts that mention a user, newest first.
+   */
+  pub fn get_mentions(&self, username: &str, page: &PageRequest) -> Result<Page<Post>> {
+    let _timer = metrics().time_query("get_mentions");
+
+    self.paginate_posts("
+      FROM mentions
+      JOIN posts ON posts.hash = mentions.post
+      JOIN users ON users.public_key = posts.author
+      WHERE mentions.username = lower(?)
+    ", vec![Value::from(username.to_string())], page)
+  }
+
+  /**
+   * Retrieve the most used hashtags in posts made since the given time.
+   */
+  pub fn trending_hashtags(&self, since: u64, limit: usize) -> Result<Vec<Trend>> {
+    let _timer = metrics().time_query("trending_hashtags");
+
+    let trends = self.sqlite
+      .prepare("
+        SELECT
+          hashtags.tag,
+          COUNT(*) AS posts,
+          COUNT(DISTINCT posts.author) AS authors
+        FROM hashtags
+        JOIN posts ON posts.hash = hashtags.post
+        WHERE hashtags.timestamp >= ?1
+        GROUP BY hashtags.tag
+        ORDER BY authors DESC, posts DESC, hashtags.tag
+        LIMIT ?2
       ")?
-      .query_map([hash], |row| {
-        Ok(Post {
-          author:    User {
-            display_name: row.get("display_name")?,
-            username:     row.get("username")?,
-            biography:    row.get("biography")?,
-            public_key:   row.get("public_key")?,
-          },
-          hash:      row.get("hash")?,
-          body:      row.get("body")?,
-          reply:     row.get::<_, Option<String>>("reply")?,
-          timestamp: row.get::<_, i64>("timestamp")? as u64,
+      .query_map(params![since, limit], |row| {
+        Ok(Trend {
+          tag:     row.get("tag")?,
+          posts:   row.get::<_, i64>("posts")? as u64,
+          authors: row.get::<_, i64>("authors")? as u64,
         })
       })?
-      .collect::<Result<Vec<Post>, _>>()?;
-    Ok(posts)
+      .collect::<Result<Vec<Trend>, _>>()?;
+    Ok(trends)
   }
 
   /**
    * Retrieve a user by their username.
    */
   pub fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+    let _timer = metrics().time_query("get_user_by_username");
+
     self.sqlite.query_row("
       SELECT
         display_name,
@@ -296,7 +891,7 @@ impl Index {
         biography,
         public_key
       FROM users
-      WHERE users.username = ?
+      WHERE users.username = ? COLLATE NOCASE
     ", [username], |row| {
       Ok(User {
         display_name: row.get("display_name")?,
@@ -311,6 +906,8 @@ impl Index {
    * Retrieve a user by their username.
    */
   pub fn get_user_by_public_key(&self, public_key: &str) -> Result<Option<User>> {
+    let _timer = metrics().time_query("get_user_by_public_key");
+
     self.sqlite.query_row("
       SELECT
         display_name,
@@ -329,40 +926,758 @@ impl Index {
     }).optional()
   }
 
-  pub fn search_users(&self, username: String) -> Result<Vec<User>> {
+  /**
+   * Search users by username, display name and biography. Every term is
+   * matched as a prefix so partial usernames find their user. Matches on the
+   * username rank above matches on the display name and biography.
+   */
+  pub fn search_users(&self, query: &str, page: &PageRequest) -> Result<Page<User>> {
+    let _timer = metrics().time_query("search_users");
+
+    let Some(query) = match_query(query, true) else {
+      return Ok(Page::empty());
+    };
+
+    let (offset, limit) = ranked_window(page);
+
     let users = self.sqlite
       .prepare("
         SELECT
-          display_name,
-          username,
-          biography,
-          public_key
-        FROM users
-        WHERE users.username LIKE ?
+          users.display_name,
+          users.username,
+          users.biography,
+          users.public_key
+        FROM users_fts
+        JOIN users ON users.rowid = users_fts.rowid
+        WHERE users_fts MATCH ?1
+        ORDER BY bm25(users_fts, 10.0, 5.0, 1.0)
+        LIMIT ?2
+        OFFSET ?3
       ")?
-      .query_map([format!("%{}%", username)], |row| {
-        Ok(User {
-          display_name: row.get("display_name")?,
-          username:     row.get("username")?,
-          biography:    row.get("biography")?,
-          public_key:   row.get("public_key")?,
-        })
-      })?
+      .query_map(params![query, limit + 1, offset], map_user)?
       .collect::<Result<Vec<User>, _>>()?;
-    Ok(users)
+
+    Ok(ranked_page(users, offset, limit))
+  }
+
+  /**
+   * Search post bodies, best matches first.
+   */
+  pub fn search_posts(&self, search: &PostSearch) -> Result<Page<Post>> {
+    let _timer = metrics().time_query("search_posts");
+
+    let Some(query) = match_query(&search.query, false) else {
+      return Ok(Page::empty());
+    };
+
+    let (offset, limit) = ranked_window(&search.page);
+
+    let posts = self.sqlite
+      .prepare(&format!("
+        SELECT {}
+        FROM posts_fts
+        JOIN posts ON posts.rowid = posts_fts.rowid
+        JOIN users ON users.public_key = posts.author
+        WHERE posts_fts MATCH ?1
+          AND (?2 IS NULL OR users.username = ?2 COLLATE NOCASE)
+          AND (?3 IS NULL OR posts.timestamp >= ?3)
+          AND (?4 IS NULL OR posts.timestamp <= ?4)
+        ORDER BY posts_fts.rank
+        LIMIT ?5
+        OFFSET ?6
+      ", POST_COLUMNS))?
+      .query_map(params![
+        query,
+        search.author,
+        search.since,
+        search.until,
+        limit + 1,
+        offset,
+      ], map_post)?
+      .collect::<Result<Vec<Post>, _>>()?;
+
+    Ok(ranked_page(posts, offset, limit))
+  }
+
+  /**
+   * Retrieve a page of posts, newest first. `from` is the rest of the query
+   * after the selected columns and has to end in a WHERE clause.
+   */
+  fn paginate_posts(&self, from: &str, mut params: Vec<Value>, page: &PageRequest) -> Result<Page<Post>> {
+    let newer = page.after.is_some();
+    let mut query = format!("SELECT {} {}", POST_COLUMNS, from);
+
+    if let Some(Cursor::Chain { height, timestamp, hash }) = page.after.as_ref().or(page.before.as_ref()) {
+      let op = if newer { ">" } else { "<" };
+      query.push_str(&format!(" AND (posts.height, posts.timestamp, posts.hash) {} (?, ?, ?)", op));
+
+      params.push(Value::from(*height as i64));
+      params.push(Value::from(*timestamp as i64));
+      params.push(Value::from(hash.clone()));
+    }
+
+    // Entries right after a cursor are the oldest ones that are newer than
+    // it, so paging towards newer entries reads in ascending order.
+    let order = if newer { "ASC" } else { "DESC" };
+    query.push_str(&format!("
+      ORDER BY posts.height {0}, posts.timestamp {0}, posts.hash {0}
+      LIMIT ?
+    ", order));
+
+    params.push(Value::from(page.limit as i64 + 1));
+
+    let mut posts = self.sqlite
+      .prepare(&query)?
+      .query_map(params_from_iter(params), map_post)?
+      .collect::<Result<Vec<Post>, _>>()?;
+
+    let more = posts.len() > page.limit;
+    posts.truncate(page.limit);
+
+    if newer {
+      posts.reverse();
+    }
+
+    let cursor = |post: &Post| Cursor::Chain {
+      height:    post.height,
+      timestamp: post.timestamp,
+      hash:      post.hash.clone(),
+    }.encode();
+
+    // Paging towards newer entries always leaves the cursor entry behind.
+    let next = if more || newer {
+      posts.last().map(cursor)
+    } else {
+      None
+    };
+
+    Ok(Page {
+      prev: posts.first().map(cursor),
+      next,
+      items: posts,
+    })
   }
 
   pub fn has_username(&self, username: &str) -> Result<bool> {
+    let _timer = metrics().time_query("has_username");
+
     let res = self.sqlite
-      .query_row("SELECT 1 FROM users WHERE username = ?", [&username], |row| row.get::<_, i32>(0))
+      .query_row("SELECT 1 FROM users WHERE username = ? COLLATE NOCASE", [&username], |row| row.get::<_, i32>(0))
       .optional()?;
     Ok(res.is_some())
   }
 
   pub fn has_pubkey(&self, public_key: &str) -> Result<bool> {
+    let _timer = metrics().time_query("has_pubkey");
+
     let res = self.sqlite
       .query_row("SELECT 1 FROM users WHERE public_key = ?", [&public_key], |row| row.get::<_, i32>(0))
       .optional()?;
     Ok(res.is_some())
   }
 }
+
+fn map_post(row: &Row) -> Result<Post> {
+  Ok(Post {
+    author:    map_user(row)?,
+    hash:      row.get("hash")?,
+    body:      row.get("body")?,
+    reply:     row.get::<_, Option<String>>("reply")?,
+    timestamp: row.get::<_, i64>("timestamp")? as u64,
+    height:    row.get::<_, i64>("height")? as u64,
+  })
+}
+
+/**
+ * Attach the replies to a post from the rows read for its thread. Each list
+ * of replies holds one more than the limit if there are more.
+ */
+fn build_thread_node(node: ThreadPost, children: &mut HashMap<String, Vec<ThreadPost>>, limit: usize) -> ThreadNode {
+  let mut replies = children
+    .remove(&node.post.hash)
+    .unwrap_or_default();
+
+  let more = replies.len() > limit;
+  replies.truncate(limit);
+
+  let next = if more {
+    replies.last().map(|reply| Cursor::Chain {
+      height:    reply.post.height,
+      timestamp: reply.post.timestamp,
+      hash:      reply.post.hash.clone(),
+    }.encode())
+  } else {
+    None
+  };
+
+  ThreadNode {
+    post:        node.post,
+    reply_count: node.reply_count,
+    replies:     replies
+      .into_iter()
+      .map(|reply| build_thread_node(reply, children, limit))
+      .collect(),
+    next,
+  }
+}
+
+/**
+ * The offset and limit of a page of a ranked list.
+ */
+fn ranked_window(page: &PageRequest) -> (usize, usize) {
+  match (&page.before, &page.after) {
+    (_, Some(Cursor::Offset(end))) => (end.saturating_sub(page.limit), page.limit.min(*end)),
+    (Some(Cursor::Offset(start)), _) => (*start, page.limit),
+    _ => (0, page.limit),
+  }
+}
+
+/**
+ * Build a page of a ranked list from a window that was read with one extra
+ * entry to see if there are more.
+ */
+fn ranked_page<T>(mut items: Vec<T>, offset: usize, limit: usize) -> Page<T> {
+  let more = items.len() > limit;
+  items.truncate(limit);
+
+  Page {
+    next:  more.then(|| Cursor::Offset(offset + items.len()).encode()),
+    prev:  (offset > 0).then(|| Cursor::Offset(offset).encode()),
+    items,
+  }
+}
+
+fn map_user(row: &Row) -> Result<User> {
+  Ok(User {
+    display_name: row.get("display_name")?,
+    username:     row.get("username")?,
+    biography:    row.get("biography")?,
+    public_key:   row.get("public_key")?,
+  })
+}
+
+/**
+ * Turn a search from user input into an FTS5 match expression. Words and
+ * "quoted phrases" are passed on as quoted strings so that user input can't
+ * use the FTS5 query syntax, and a trailing `*` is kept as a prefix match.
+ * Returns `None` when there is nothing to search for.
+ */
+fn match_query(input: &str, prefix: bool) -> Option<String> {
+  let mut terms: Vec<String> = vec![];
+  let mut rest = input;
+
+  while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+    rest = &rest[start..];
+
+    let (term, is_prefix) = if let Some(phrase) = rest.strip_prefix('"') {
+      let end = phrase.find('"').unwrap_or(phrase.len());
+      let term = &phrase[..end];
+      rest = phrase.get(end + 1..).unwrap_or("");
+      (term, false)
+    } else {
+      let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+      let term = &rest[..end];
+      rest = &rest[end..];
+      match term.strip_suffix('*') {
+        Some(term) => (term, true),
+        None       => (term, prefix),
+      }
+    };
+
+    let term: String = term
+      .chars()
+      .map(|c| if c == '"' || c == '*' { ' ' } else { c })
+      .collect();
+
+    if term.trim().is_empty() {
+      continue;
+    }
+
+    let star = if is_prefix { "*" } else { "" };
+    terms.push(format!("\"{}\"{}", term.trim(), star));
+  }
+
+  if terms.is_empty() {
+    None
+  } else {
+    Some(terms.join(" "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::temp_dir;
+    use crate::blockchain::chain::Blockchain;
+    use crate::blockchain::genesis::Genesis;
+
+    fn user_block(index: u64, username: &str) -> Block {
+      let mut block = Block::new(BlockData::User {
+        display_name: username.to_string(),
+        username:     username.to_string(),
+        biography:    "".to_string(),
+      }, index, "0".to_string());
+
+      block.public_key = format!("key-{}", username);
+      block
+    }
+
+    fn post_block(index: u64, username: &str, body: &str, timestamp: u64) -> Block {
+      let mut block = Block::new(BlockData::Post {
+        body:  body.to_string(),
+        reply: None,
+      }, index, "0".to_string());
+
+      block.public_key = format!("key-{}", username);
+      block.timestamp = timestamp;
+      block.hash = block.hash_block();
+      block
+    }
+
+    fn search_index() -> Index {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.add_block(user_block(2, "bob")).unwrap();
+      index.add_block(post_block(3, "alice", "Rust makes blockchains fun", 100)).unwrap();
+      index.add_block(post_block(4, "bob", "fun with rust, more rust and even more rust", 200)).unwrap();
+      index.add_block(post_block(5, "bob", "blockchains are not fun", 300)).unwrap();
+      index
+    }
+
+    fn search(index: &Index, search: PostSearch) -> Vec<String> {
+      index.search_posts(&PostSearch { page: PageRequest::first(10), ..search })
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|post| post.body)
+        .collect()
+    }
+
+    #[test]
+    fn test_new_index_is_at_latest_version() {
+      let index = Index::open(":memory:").unwrap();
+
+      assert_eq!(index.schema_version().unwrap(), Index::latest_version());
+      assert_eq!(index.height().unwrap(), None);
+      assert!(!index.was_rebuilt());
+    }
+
+    #[test]
+    fn test_current_index_is_kept() {
+      let path = temp_dir().join("chainindex.db");
+
+      let index = Index::open(&path).unwrap();
+      index.add_block(user_block(1, "alice")).unwrap();
+      drop(index);
+
+      let index = Index::open(&path).unwrap();
+
+      assert!(!index.was_rebuilt());
+      assert_eq!(index.height().unwrap(), Some(1));
+      assert!(index.has_username("alice").unwrap());
+    }
+
+    #[test]
+    fn test_unversioned_index_is_rebuilt() {
+      let path = temp_dir().join("chainindex.db");
+      let sqlite = Connection::open(&path).unwrap();
+
+      sqlite.execute_batch("
+        CREATE TABLE posts (
+          hash      TEXT PRIMARY KEY,
+          author    TEXT NOT NULL,
+          body      TEXT NOT NULL,
+          reply     TEXT,
+          timestamp INTEGER NOT NULL
+        );
+        CREATE TABLE users (
+          public_key   TEXT PRIMARY KEY,
+          username     TEXT NOT NULL,
+          display_name TEXT NOT NULL,
+          biography    TEXT
+        );
+        INSERT INTO users VALUES ('key-alice', 'alice', 'Alice', '');
+      ").unwrap();
+      drop(sqlite);
+
+      let index = Index::open(&path).unwrap();
+
+      // Hashtags can't be backfilled in place.
+      assert_eq!(index.schema_version().unwrap(), Index::latest_version());
+      assert!(index.was_rebuilt());
+      assert!(!index.has_username("alice").unwrap());
+    }
+
+    #[test]
+    fn test_index_from_newer_version_is_rebuilt() {
+      let path = temp_dir().join("chainindex.db");
+
+      let index = Index::open(&path).unwrap();
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.sqlite.execute("UPDATE schema_version SET version = 999", []).unwrap();
+      drop(index);
+
+      let index = Index::open(&path).unwrap();
+
+      assert!(index.was_rebuilt());
+      assert_eq!(index.schema_version().unwrap(), Index::latest_version());
+      assert_eq!(index.height().unwrap(), None);
+      assert!(!index.has_username("alice").unwrap());
+    }
+
+    #[test]
+    fn test_chain_rebuilds_index_from_store() {
+      let dir = temp_dir();
+      let devnet = Genesis::profile("devnet").unwrap();
+
+      let chain = Blockchain::open(&dir, &devnet).unwrap();
+      chain.store.put_block(user_block(1, "alice")).unwrap();
+      chain.index.sqlite.execute("UPDATE schema_version SET version = 999", []).unwrap();
+      drop(chain);
+
+      let chain = Blockchain::open(&dir, &devnet).unwrap();
+
+      assert!(chain.index.was_rebuilt());
+      assert_eq!(chain.index.height().unwrap(), Some(1));
+      assert!(chain.index.has_username("alice").unwrap());
+    }
+
+    #[test]
+    fn test_search_posts_ranks_best_match_first() {
+      let index = search_index();
+      let posts = search(&index, PostSearch { query: "rust".to_string(), ..Default::default() });
+
+      assert_eq!(posts, vec![
+        "fun with rust, more rust and even more rust",
+        "Rust makes blockchains fun",
+      ]);
+    }
+
+    #[test]
+    fn test_search_posts_phrases_and_prefixes() {
+      let index = search_index();
+
+      let posts = search(&index, PostSearch { query: "\"not fun\"".to_string(), ..Default::default() });
+      assert_eq!(posts, vec!["blockchains are not fun"]);
+
+      let posts = search(&index, PostSearch { query: "block*".to_string(), ..Default::default() });
+      assert_eq!(posts.len(), 2);
+
+      let posts = search(&index, PostSearch { query: "block".to_string(), ..Default::default() });
+      assert!(posts.is_empty());
+    }
+
+    #[test]
+    fn test_search_posts_filters() {
+      let index = search_index();
+
+      let posts = search(&index, PostSearch {
+        query:  "fun".to_string(),
+        author: Some("bob".to_string()),
+        ..Default::default()
+      });
+      assert_eq!(posts.len(), 2);
+
+      // Usernames are matched ignoring case.
+      let posts = search(&index, PostSearch {
+        query:  "fun".to_string(),
+        author: Some("BoB".to_string()),
+        ..Default::default()
+      });
+      assert_eq!(posts.len(), 2);
+
+      let posts = search(&index, PostSearch {
+        query: "fun".to_string(),
+        since: Some(150),
+        until: Some(250),
+        ..Default::default()
+      });
+      assert_eq!(posts, vec!["fun with rust, more rust and even more rust"]);
+    }
+
+    #[test]
+    fn test_usernames_are_case_insensitive() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "Alice")).unwrap();
+      index.add_block(user_block(2, "alice")).unwrap();
+
+      assert!(index.has_username("ALICE").unwrap());
+      assert!(!index.has_pubkey("key-alice").unwrap());
+      assert_eq!(index.get_user_by_username("alice").unwrap().unwrap().public_key, "key-Alice");
+    }
+
+    #[test]
+    fn test_search_users_matches_prefixes_and_profiles() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "hbackman")).unwrap();
+      index.add_block(Block {
+        public_key: "key-hbackman".to_string(),
+        ..Block::new(BlockData::UserUpdate {
+          display_name: "Hampus".to_string(),
+          biography:    "Writes rust on weekends".to_string(),
+        }, 2, "0".to_string())
+      }).unwrap();
+
+      let usernames = |q: &str| -> Vec<String> {
+        index.search_users(q, &PageRequest::first(10))
+          .unwrap()
+          .items
+          .into_iter()
+          .map(|u| u.username)
+          .collect()
+      };
+
+      assert_eq!(usernames("hback"), vec!["hbackman"]);
+      assert_eq!(usernames("hampus"), vec!["hbackman"]);
+      assert_eq!(usernames("weekend"), vec!["hbackman"]);
+      assert!(usernames("java").is_empty());
+    }
+
+    #[test]
+    fn test_match_query_escapes_fts_syntax() {
+      assert_eq!(match_query("rust", false).unwrap(), "\"rust\"");
+      assert_eq!(match_query("rust", true).unwrap(), "\"rust\"*");
+      assert_eq!(match_query("ru* \"hello world\"", false).unwrap(), "\"ru\"* \"hello world\"");
+      assert_eq!(match_query("a OR b NEAR(", false).unwrap(), "\"a\" \"OR\" \"b\" \"NEAR(\"");
+      assert_eq!(match_query("\"unterminated", false).unwrap(), "\"unterminated\"");
+      assert_eq!(match_query("  * \"\" ", false), None);
+    }
+
+    #[test]
+    fn test_hashtag_and_mention_timelines() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.add_block(user_block(2, "bob")).unwrap();
+      index.add_block(post_block(3, "alice", "hello #Rust @bob", 100)).unwrap();
+      index.add_block(post_block(4, "bob", "#rust is nice, right @Alice?", 200)).unwrap();
+      index.add_block(post_block(5, "bob", "#go #rust", 300)).unwrap();
+
+      let bodies = |posts: Page<Post>| -> Vec<String> {
+        posts.items.into_iter().map(|p| p.body).collect()
+      };
+
+      let first = index.get_hashtag_posts("RUST", &PageRequest::first(2)).unwrap();
+      let next = PageRequest {
+        before: Cursor::decode(first.next.as_ref().unwrap()),
+        ..PageRequest::first(2)
+      };
+
+      assert_eq!(bodies(first), vec![
+        "#go #rust",
+        "#rust is nice, right @Alice?",
+      ]);
+      assert_eq!(bodies(index.get_hashtag_posts("rust", &next).unwrap()), vec![
+        "hello #Rust @bob",
+      ]);
+      assert_eq!(bodies(index.get_mentions("alice", &PageRequest::first(10)).unwrap()), vec![
+        "#rust is nice, right @Alice?",
+      ]);
+    }
+
+    #[test]
+    fn test_trending_hashtags_in_window() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.add_block(user_block(2, "bob")).unwrap();
+      index.add_block(post_block(3, "alice", "#old #old2", 100)).unwrap();
+      index.add_block(post_block(4, "alice", "#solo", 200)).unwrap();
+      index.add_block(post_block(5, "alice", "#solo again", 210)).unwrap();
+      index.add_block(post_block(6, "alice", "#shared", 220)).unwrap();
+      index.add_block(post_block(7, "bob", "#shared too", 230)).unwrap();
+
+      let trends: Vec<(String, u64, u64)> = index.trending_hashtags(150, 10)
+        .unwrap()
+        .into_iter()
+        .map(|t| (t.tag, t.posts, t.authors))
+        .collect();
+
+      assert_eq!(trends, vec![
+        ("shared".to_string(), 2, 2),
+        ("solo".to_string(), 2, 1),
+      ]);
+    }
+
+    fn feed_page(index: &Index, page: &PageRequest) -> (Vec<u64>, Option<String>, Option<String>) {
+      let feed = index.get_feed(vec!["alice".to_string()], page).unwrap();
+      let heights = feed.items.iter().map(|p| p.height).collect();
+      (heights, feed.next, feed.prev)
+    }
+
+    fn cursor(encoded: &Option<String>) -> Option<Cursor> {
+      Cursor::decode(encoded.as_ref().unwrap())
+    }
+
+    #[test]
+    fn test_feed_is_newest_first_with_stable_cursors() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      for height in 2..=6 {
+        index.add_block(post_block(height, "alice", &format!("post {}", height), 100)).unwrap();
+      }
+
+      let (heights, next, prev) = feed_page(&index, &PageRequest::first(2));
+      assert_eq!(heights, vec![6, 5]);
+      assert!(prev.is_some());
+
+      // New blocks don't move the pages that come after a cursor.
+      index.add_block(post_block(7, "alice", "post 7", 100)).unwrap();
+
+      let older = PageRequest { before: cursor(&next), ..PageRequest::first(2) };
+      let (heights, next, prev) = feed_page(&index, &older);
+      assert_eq!(heights, vec![4, 3]);
+
+      let older = PageRequest { before: cursor(&next), ..PageRequest::first(2) };
+      let (heights, next, _) = feed_page(&index, &older);
+      assert_eq!(heights, vec![2]);
+      assert_eq!(next, None);
+
+      let newer = PageRequest { after: cursor(&prev), ..PageRequest::first(2) };
+      let (heights, _, prev) = feed_page(&index, &newer);
+      assert_eq!(heights, vec![6, 5]);
+
+      let newer = PageRequest { after: cursor(&prev), ..PageRequest::first(2) };
+      let (heights, _, prev) = feed_page(&index, &newer);
+      assert_eq!(heights, vec![7]);
+
+      let newer = PageRequest { after: cursor(&prev), ..PageRequest::first(2) };
+      let (heights, _, prev) = feed_page(&index, &newer);
+      assert!(heights.is_empty());
+      assert_eq!(prev, None);
+    }
+
+    #[test]
+    fn test_search_pages_by_rank() {
+      let index = search_index();
+      let search = |page: PageRequest| {
+        index.search_posts(&PostSearch {
+          query: "fun".to_string(),
+          page,
+          ..Default::default()
+        }).unwrap()
+      };
+
+      let first = search(PageRequest::first(2));
+      assert_eq!(first.items.len(), 2);
+      assert_eq!(first.prev, None);
+
+      let second = search(PageRequest { before: cursor(&first.next), ..PageRequest::first(2) });
+      assert_eq!(second.items.len(), 1);
+      assert_eq!(second.next, None);
+
+      let back = search(PageRequest { after: cursor(&second.prev), ..PageRequest::first(2) });
+      let hashes = |page: &Page<Post>| page.items.iter().map(|p| p.hash.clone()).collect::<Vec<_>>();
+      assert_eq!(hashes(&back), hashes(&first));
+    }
+
+    fn reply_block(index: u64, username: &str, reply: &str) -> Block {
+      let mut block = Block::new(BlockData::Post {
+        body:  format!("reply {}", index),
+        reply: Some(reply.to_string()),
+      }, index, "0".to_string());
+
+      block.public_key = format!("key-{}", username);
+      block.hash = format!("post-{}", index);
+      block
+    }
+
+    fn thread_shape(node: &ThreadNode) -> String {
+      let replies: Vec<String> = node.replies.iter().map(thread_shape).collect();
+      format!("{}[{}]({})", node.post.height, node.reply_count, replies.join(","))
+    }
+
+    /**
+     * 2 <- 3 <- 4 <- 5 <- 9
+     *        <- 6
+     *        <- 7 <- 8
+     */
+    fn thread_index() -> Index {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.add_block(reply_block(2, "alice", "none")).unwrap();
+      index.add_block(reply_block(3, "alice", "post-2")).unwrap();
+      index.add_block(reply_block(4, "alice", "post-3")).unwrap();
+      index.add_block(reply_block(5, "alice", "post-4")).unwrap();
+      index.add_block(reply_block(6, "alice", "post-3")).unwrap();
+      index.add_block(reply_block(7, "alice", "post-3")).unwrap();
+      index.add_block(reply_block(8, "alice", "post-7")).unwrap();
+      index.add_block(reply_block(9, "alice", "post-5")).unwrap();
+      index
+    }
+
+    #[test]
+    fn test_thread_has_ancestors_and_reply_tree() {
+      let index = thread_index();
+      let thread = index.get_thread("post-4", 5, &PageRequest::first(10)).unwrap().unwrap();
+
+      let ancestors: Vec<u64> = thread.ancestors.iter().map(|a| a.post.height).collect();
+      assert_eq!(ancestors, vec![2, 3]);
+      assert_eq!(thread.ancestors[1].reply_count, 3);
+      assert_eq!(thread_shape(&thread.post), "4[1](5[1](9[0]()))");
+
+      let thread = index.get_thread("post-2", 5, &PageRequest::first(10)).unwrap().unwrap();
+      assert!(thread.ancestors.is_empty());
+      assert_eq!(thread_shape(&thread.post), "2[1](3[3](4[1](5[1](9[0]())),6[0](),7[1](8[0]())))");
+    }
+
+    #[test]
+    fn test_thread_is_depth_limited() {
+      let index = thread_index();
+      let thread = index.get_thread("post-2", 2, &PageRequest::first(10)).unwrap().unwrap();
+
+      assert_eq!(thread_shape(&thread.post), "2[1](3[3](4[1](),6[0](),7[1]()))");
+    }
+
+    #[test]
+    fn test_thread_pages_through_replies() {
+      let index = thread_index();
+      let thread = index.get_thread("post-3", 1, &PageRequest::first(2)).unwrap().unwrap();
+
+      assert_eq!(thread_shape(&thread.post), "3[3](4[1](),6[0]())");
+
+      let next = PageRequest {
+        after: Cursor::decode(thread.post.next.as_ref().unwrap()),
+        ..PageRequest::first(2)
+      };
+      let thread = index.get_thread("post-3", 1, &next).unwrap().unwrap();
+
+      assert_eq!(thread_shape(&thread.post), "3[3](7[1]())");
+      assert_eq!(thread.post.next, None);
+
+      // Nested replies are cut off at the limit too.
+      let thread = index.get_thread("post-2", 2, &PageRequest::first(1)).unwrap().unwrap();
+      assert_eq!(thread_shape(&thread.post), "2[1](3[3](4[1]()))");
+      assert!(thread.post.replies[0].next.is_some());
+    }
+
+    #[test]
+    fn test_thread_cap_leaves_room_for_deeper_replies() {
+      let index = Index::open(":memory:").unwrap();
+
+      index.add_block(user_block(1, "alice")).unwrap();
+      index.add_block(reply_block(2, "alice", "none")).unwrap();
+      index.add_block(reply_block(3, "alice", "post-2")).unwrap();
+      index.add_block(reply_block(4, "alice", "post-3")).unwrap();
+
+      for height in 5..(MAX_THREAD_POSTS as u64 + 10) {
+        index.add_block(reply_block(height, "alice", "post-2")).unwrap();
+      }
+
+      let thread = index.get_thread("post-2", 2, &PageRequest::first(2)).unwrap().unwrap();
+
+      assert_eq!(thread_shape(&thread.post), format!("2[{}](3[1](4[0]()),5[0]())", MAX_THREAD_POSTS + 6));
+    }
+
+    #[test]
+    fn test_thread_of_missing_post() {
+      let index = thread_index();
+
+      assert!(index.get_thread("missing", 3, &PageRequest::first(10)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,257 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+use tracing::{info, warn};
+use crate::blockchain::block::{hash_nonce, meets_difficulty, Block, PendingBlock};
+use crate::blockchain::handle::ChainHandle;
+
+// How many nonces a worker tries between checks for cancellation.
+const BATCH: u64 = 1024;
+
+/**
+ * Mines blocks on worker threads, off the async runtime and without holding
+ * the chain lock. Work on a block is abandoned and started over on the new top
+ * block whenever the tip of the chain changes.
+ */
+#[derive(Debug)]
+pub struct Miner {
+  threads:   usize,
+  // Hashes per second over the last block mined.
+  hash_rate: AtomicU64,
+  // Set when the node shuts down, which abandons the work in progress.
+  stopped:   AtomicBool,
+}
+
+impl Miner {
+  pub fn new(threads: usize) -> Self {
+    Self {
+      threads:   threads.max(1),
+      hash_rate: AtomicU64::new(0),
+      stopped:   AtomicBool::new(false),
+    }
+  }
+
+  /**
+   * Stop mining for good. The block being mined is given up on.
+   */
+  pub fn stop(&self) {
+    self.stopped.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_stopped(&self) -> bool {
+    self.stopped.load(Ordering::Relaxed)
+  }
+
+  /**
+   * The number of worker threads.
+   */
+  pub fn threads(&self) -> usize {
+    self.threads
+  }
+
+  /**
+   * The hash rate over the last block mined, in hashes per second.
+   */
+  pub fn hash_rate(&self) -> u64 {
+    self.hash_rate.load(Ordering::Relaxed)
+  }
+
+  /**
+   * Mine a pending block on top of the chain and add it. Returns the block
+   * once it is on the chain, or `None` when it is no longer valid or the
+   * miner was stopped.
+   */
+  pub async fn mine_pending(self: &Arc<Self>, chain: &ChainHandle, pending: PendingBlock) -> Option<Block> {
+    loop {
+      let mut tip = chain.subscribe_tip();
+      tip.mark_unchanged();
+
+      let template = chain.write({
+        let pending = pending.clone();
+
+        move |chain| {
+          let mut block = Block::next(&chain.top_block(), pending.data);
+
+          block.timestamp  = pending.timestamp;
+          block.signature  = pending.signature;
+          block.public_key = pending.public_key;
+
+          chain.difficulty(&block).map(|difficulty| (block, difficulty))
+        }
+      }).await;
+
+      let (template, difficulty) = match template {
+        Ok(template) => template,
+        Err(e) => {
+          warn!(error = %e, "Dropping pending block");
+          return None;
+        },
+      };
+
+      let cancel = Arc::new(AtomicBool::new(false));
+      let mut work = tokio::task::spawn_blocking({
+        let miner  = self.clone();
+        let cancel = cancel.clone();
+        move || miner.mine(template, difficulty, &cancel)
+      });
+
+      let mined = tokio::select! {
+        res = &mut work => res.ok().flatten(),
+        Ok(()) = tip.changed() => {
+          cancel.store(true, Ordering::Relaxed);
+          let _ = work.await;
+          info!("Chain tip changed, restarting mining");
+          continue;
+        },
+      };
+
+      let block = mined?;
+      let added = chain.write({
+        let block = block.clone();
+
+        move |chain| match chain.add_block(block.clone()) {
+          Ok(()) => Ok(true),
+          // A block arrived between mining and adding it.
+          Err(_) if block.prev_hash != chain.top_block().hash => Ok(false),
+          Err(e) => Err(e),
+        }
+      }).await;
+
+      match added {
+        Ok(true)  => return Some(block),
+        Ok(false) => continue,
+        Err(e)    => {
+          warn!(hash = %block.hash, error = %e, "Dropping mined block");
+          return None;
+        },
+      }
+    }
+  }
+
+  /**
+   * Mine a block at the given difficulty, blocking until a worker finds a
+   * hash, `cancel` is set or the miner is stopped. Worker `i` tries the nonces `i`, `i + threads`,
+   * and so on.
+   */
+  pub fn mine(&self, mut block: Block, difficulty: usize, cancel: &AtomicBool) -> Option<Block> {
+    let (prefix, suffix) = block.hash_template();
+    let found  = AtomicBool::new(false);
+    let hashes = AtomicU64::new(0);
+    let start  = Instant::now();
+
+    let nonce = thread::scope(|scope| {
+      let workers: Vec<_> = (0..self.threads as u64)
+        .map(|worker| {
+          let (prefix, suffix) = (&prefix, &suffix);
+          let (found, hashes)  = (&found, &hashes);
+          let step = self.threads as u64;
+
+          scope.spawn(move || {
+            let mut nonce = worker;
+
+            while !found.load(Ordering::Relaxed) && !cancel.load(Ordering::Relaxed) && !self.is_stopped() {
+              for _ in 0..BATCH {
+                if meets_difficulty(&hash_nonce(prefix, suffix, nonce), difficulty) {
+                  found.store(true, Ordering::Relaxed);
+                  return Some(nonce);
+                }
+                nonce = nonce.wrapping_add(step);
+              }
+              hashes.fetch_add(BATCH, Ordering::Relaxed);
+            }
+
+            None
+          })
+        })
+        .collect();
+
+      workers
+        .into_iter()
+        .filter_map(|worker| worker.join().unwrap())
+        .min()
+    });
+
+    let elapsed = start.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+      let rate = hashes.load(Ordering::Relaxed) as f64 / elapsed;
+      self.hash_rate.store(rate as u64, Ordering::Relaxed);
+    }
+
+    block.nonce = nonce?;
+    block.hash  = block.hash_block();
+
+    info!(hash = %block.hash, nonce = block.nonce, hash_rate = self.hash_rate(), "Block mined");
+
+    Some(block)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockData;
+    use crate::blockchain::chain::Blockchain;
+    use crate::blockchain::testing::Account;
+
+    #[test]
+    fn test_mined_hash_meets_difficulty() {
+      let miner = Miner::new(4);
+      let block = Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 1, "0".to_string());
+
+      let mined = miner.mine(block, 4, &AtomicBool::new(false)).unwrap();
+
+      assert!(mined.hash.starts_with("0000"));
+      assert_eq!(mined.hash, mined.hash_block());
+    }
+
+    #[test]
+    fn test_cancelled_mining_gives_up() {
+      let miner = Miner::new(2);
+      let block = Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 1, "0".to_string());
+
+      assert!(miner.mine(block, 64, &AtomicBool::new(true)).is_none());
+    }
+
+    #[test]
+    fn test_stopped_miner_gives_up() {
+      let miner = Miner::new(2);
+      let block = Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 1, "0".to_string());
+
+      miner.stop();
+      assert!(miner.mine(block, 64, &AtomicBool::new(false)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mining_restarts_on_new_tip() {
+      let chain = ChainHandle::start(Blockchain::temp());
+      let miner = Arc::new(Miner::new(2));
+      let alice = Account::new(1);
+      let bob = Account::new(2);
+
+      let registration = |account: &Account, username: &str| account.pending(BlockData::User {
+        display_name: username.to_string(),
+        username:     username.to_string(),
+        biography:    "".to_string(),
+      });
+
+      let data = registration(&bob, "bob").data;
+      let block = chain.write(move |chain| bob.mine(chain, data)).await;
+
+      // Bob's registration takes the tip while Alice's is being mined.
+      let mining = tokio::spawn({
+        let (miner, chain) = (miner.clone(), chain.clone());
+        let pending = registration(&alice, "alice");
+        async move { miner.mine_pending(&chain, pending).await }
+      });
+
+      chain.write(move |chain| chain.add_block(block)).await.unwrap();
+
+      let mined = mining.await.unwrap().unwrap();
+      let reader = chain.reader();
+
+      assert_eq!(reader.height(), 2);
+      assert_eq!(reader.top_block().hash, mined.hash);
+      assert!(reader.store.get_account(&Account::new(2).public_key()).unwrap().is_some());
+    }
+}
@@ -1,5 +1,26 @@
 pub mod block;
 pub mod chain;
+pub mod genesis;
+pub mod handle;
+pub mod miner;
 pub mod sign;
 pub mod store;
 pub mod index;
+pub mod text;
+pub mod page;
+pub mod reader;
+pub mod rules;
+#[cfg(test)]
+pub mod testing;
+
+/**
+ * Create an empty directory for a test chain.
+ */
+#[cfg(test)]
+pub fn temp_dir() -> std::path::PathBuf {
+  let dir = std::env::temp_dir()
+    .join(format!("cryptogram-{}", uuid::Uuid::new_v4()));
+
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
@@ -0,0 +1,150 @@
+use serde::Serialize;
+
+/**
+ * A position in a list. Chronological lists are ordered newest first by block
+ * height, timestamp and hash, so a cursor on one of them stays valid as new
+ * blocks arrive. Ranked lists, like search results, have no such order and
+ * are paged by position instead.
+ *
+ * Cursors are handed out encoded and clients should treat them as opaque.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cursor {
+  Chain {
+    height:    u64,
+    timestamp: u64,
+    hash:      String,
+  },
+  Offset(usize),
+}
+
+/**
+ * How a list is ordered, which decides the kind of cursor it is paged with.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+  Chronological,
+  Ranked,
+}
+
+impl Cursor {
+  /**
+   * The order of the lists the cursor can page.
+   */
+  pub fn order(&self) -> Order {
+    match self {
+      Cursor::Chain { .. } => Order::Chronological,
+      Cursor::Offset(_)    => Order::Ranked,
+    }
+  }
+
+  pub fn encode(&self) -> String {
+    let raw = match self {
+      Cursor::Chain { height, timestamp, hash } => format!("c:{}:{}:{}", height, timestamp, hash),
+      Cursor::Offset(offset) => format!("o:{}", offset),
+    };
+    hex::encode(raw)
+  }
+
+  pub fn decode(encoded: &str) -> Option<Self> {
+    let raw = String::from_utf8(hex::decode(encoded).ok()?).ok()?;
+    let parts: Vec<&str> = raw.split(':').collect();
+
+    match parts.as_slice() {
+      ["c", height, timestamp, hash] => Some(Cursor::Chain {
+        height:    height.parse().ok()?,
+        timestamp: timestamp.parse().ok()?,
+        hash:      hash.to_string(),
+      }),
+      ["o", offset] => Some(Cursor::Offset(offset.parse().ok()?)),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * A request for a page of a list. `before` pages towards older entries and
+ * `after` towards newer ones, at most one of them is set.
+ */
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+  pub before: Option<Cursor>,
+  pub after:  Option<Cursor>,
+  pub limit:  usize,
+}
+
+impl Default for PageRequest {
+  fn default() -> Self {
+    Self::first(32)
+  }
+}
+
+impl PageRequest {
+  pub fn first(limit: usize) -> Self {
+    Self {
+      before: None,
+      after:  None,
+      limit,
+    }
+  }
+}
+
+/**
+ * A page of a list. `next` is passed as `before` to continue with older (or
+ * lower ranked) entries and is only set when there are more of them. `prev`
+ * is passed as `after` to go back. On chronological lists it is set whenever
+ * the page has entries, so it can also be used to poll for new entries.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub next:  Option<String>,
+  pub prev:  Option<String>,
+}
+
+impl<T> Page<T> {
+  pub fn empty() -> Self {
+    Self {
+      items: vec![],
+      next:  None,
+      prev:  None,
+    }
+  }
+
+  /**
+   * Transform the entries of the page, keeping the cursors.
+   */
+  pub fn try_map<U, E, F>(self, f: F) -> Result<Page<U>, E>
+  where F: FnOnce(Vec<T>) -> Result<Vec<U>, E>
+  {
+    Ok(Page {
+      items: f(self.items)?,
+      next:  self.next,
+      prev:  self.prev,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+      let chain = Cursor::Chain {
+        height:    42,
+        timestamp: 1700000000,
+        hash:      "00abc".to_string(),
+      };
+
+      assert_eq!(Cursor::decode(&chain.encode()), Some(chain));
+      assert_eq!(Cursor::decode(&Cursor::Offset(64).encode()), Some(Cursor::Offset(64)));
+    }
+
+    #[test]
+    fn test_cursor_rejects_garbage() {
+      assert_eq!(Cursor::decode("not hex"), None);
+      assert_eq!(Cursor::decode(&hex::encode("c:1:two:abc")), None);
+      assert_eq!(Cursor::decode(&hex::encode("x:1")), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::blockchain::block::Block;
+use crate::blockchain::index::Index;
+use crate::blockchain::store::Store;
+
+// Idle index connections kept around for the next reader.
+const IDLE_CONNECTIONS: usize = 8;
+
+/**
+ * Read access to the chain that doesn't go through the writer. Block storage
+ * is shared with the writer, LMDB serves reads next to a write transaction,
+ * and index queries run on a pool of read-only connections.
+ */
+#[derive(Debug, Clone)]
+pub struct ChainReader {
+  pub store:  Store,
+  path:       PathBuf,
+  idle:       Arc<Mutex<Vec<Index>>>,
+  // Kept up to date by the writer.
+  mpool_size: Arc<AtomicUsize>,
+}
+
+/**
+ * An index connection borrowed from the reader. It goes back to the pool when
+ * dropped.
+ */
+pub struct PooledIndex {
+  index: Option<Index>,
+  idle:  Arc<Mutex<Vec<Index>>>,
+}
+
+impl ChainReader {
+  pub fn new(store: Store, path: PathBuf, mpool_size: Arc<AtomicUsize>) -> Self {
+    Self {
+      store,
+      path,
+      idle: Arc::new(Mutex::new(vec![])),
+      mpool_size,
+    }
+  }
+
+  /**
+   * Borrow a read-only connection to the index.
+   */
+  pub fn index(&self) -> rusqlite::Result<PooledIndex> {
+    let idle = self.idle
+      .lock()
+      .unwrap()
+      .pop();
+
+    let index = match idle {
+      Some(index) => index,
+      None        => Index::open_reader(&self.path)?,
+    };
+
+    Ok(PooledIndex {
+      index: Some(index),
+      idle:  self.idle.clone(),
+    })
+  }
+
+  /**
+   * Retrieve the chain height.
+   */
+  pub fn height(&self) -> u64 {
+    self.store.get_height().unwrap()
+  }
+
+  /**
+   * The number of blocks in the memory pool.
+   */
+  pub fn mempool_size(&self) -> usize {
+    self.mpool_size.load(Ordering::Relaxed)
+  }
+
+  /**
+   * Retrieve a block at the given index.
+   */
+  pub fn at(&self, index: usize) -> Option<Block> {
+    self.store
+      .get_block(index as u64)
+      .unwrap()
+  }
+
+  /**
+   * Retrieve the latest block.
+   */
+  pub fn top_block(&self) -> Block {
+    self.store.top_block().unwrap()
+  }
+
+  /**
+   * Print the chain to stdout.
+   */
+  pub fn print_chain(&self) {
+    println!("==================================================================================");
+    for block in self.chain_iter() {
+      let json = serde_json::to_string_pretty(&block)
+        .unwrap();
+
+      println!("{}", json);
+      println!("==================================================================================");
+    }
+  }
+
+  pub fn chain_iter(&self) -> impl Iterator<Item = Block> + '_ {
+    (0..=self.height()).map(move |i| {
+      self.store.get_block(i)
+        .unwrap()
+        .unwrap()
+    })
+  }
+}
+
+impl Deref for PooledIndex {
+  type Target = Index;
+
+  fn deref(&self) -> &Index {
+    self.index.as_ref().unwrap()
+  }
+}
+
+impl Drop for PooledIndex {
+  fn drop(&mut self) {
+    let mut idle = self.idle.lock().unwrap();
+
+    if idle.len() < IDLE_CONNECTIONS {
+      idle.extend(self.index.take());
+    }
+  }
+}
@@ -0,0 +1,236 @@
+use crate::blockchain::block::BlockData;
+use crate::blockchain::chain::Blockchain;
+use crate::blockchain::text;
+
+/**
+ * Who is allowed to sign a transaction.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signer {
+  // Nobody, the transaction is only valid as the first block.
+  Nobody,
+  // A key that has not registered an account yet.
+  Unregistered,
+  // A key with a registered account.
+  Registered,
+}
+
+/**
+ * An upper bound on the length of a text field, in grapheme clusters.
+ */
+#[derive(Debug)]
+pub struct Limit {
+  pub field: &'static str,
+  pub label: &'static str,
+  pub max:   usize,
+}
+
+/**
+ * The consensus rules of a transaction type. Every block, and every pending
+ * block before it enters the memory pool, is checked against the rules of its
+ * transaction.
+ */
+#[derive(Debug)]
+pub struct Rules {
+  pub signer:     Signer,
+  pub difficulty: usize,
+  pub limits:     &'static [Limit],
+  // Checks against the chain state that only apply to this transaction type,
+  // given the signer and the height the transaction is mined at.
+  pub validate:   fn(&Blockchain, &BlockData, &str, u64) -> Result<(), String>,
+}
+
+const DISPLAY_NAME: Limit = Limit { field: "display_name", label: "Display name", max: 255 };
+const BIOGRAPHY:    Limit = Limit { field: "biography",    label: "Biography",    max: 300 };
+
+pub const GENESIS: Rules = Rules {
+  signer:     Signer::Nobody,
+  difficulty: 0,
+  limits:     &[],
+  validate:   |_, _, _, _| Ok(()),
+};
+
+// User registration should be a little more difficult than other blocks to
+// prevent rapid registration attempts.
+pub const USER: Rules = Rules {
+  signer:     Signer::Unregistered,
+  difficulty: 5,
+  limits:     &[DISPLAY_NAME, BIOGRAPHY],
+  validate:   |chain, data, _, _| chain.validate_username(data),
+};
+
+pub const USER_UPDATE: Rules = Rules {
+  signer:     Signer::Registered,
+  difficulty: 3,
+  limits:     &[DISPLAY_NAME, BIOGRAPHY],
+  validate:   |_, _, _, _| Ok(()),
+};
+
+pub const POST: Rules = Rules {
+  signer:     Signer::Registered,
+  difficulty: 3,
+  limits:     &[
+    Limit { field: "body", label: "Post size", max: 300 },
+  ],
+  validate:   |chain, data, public_key, height| {
+    chain.validate_reply(data, height)?;
+    chain.validate_post_rate(public_key, height, 0)
+  },
+};
+
+/**
+ * The number of blocks that posts count as recent for the anti-spam policy.
+ */
+pub const POST_WINDOW: u64 = 100;
+
+/**
+ * The most posts an account can have in the recent blocks.
+ */
+pub const POST_LIMIT: usize = 10;
+
+/**
+ * The work a post needs on top of the base difficulty. Every few recent posts
+ * of the account and long bodies add a level, and each level is 16 times the
+ * work.
+ */
+pub fn post_work(recent: usize, body: &str) -> usize {
+  recent / 4 + text::length(body) / 200
+}
+
+impl BlockData {
+  /**
+   * The rules of the transaction type.
+   */
+  pub fn rules(&self) -> &'static Rules {
+    match self {
+      BlockData::Genesis { .. }    => &GENESIS,
+      BlockData::User { .. }       => &USER,
+      BlockData::UserUpdate { .. } => &USER_UPDATE,
+      BlockData::Post { .. }       => &POST,
+    }
+  }
+
+  /**
+   * Retrieve a text field of the transaction by name.
+   */
+  pub fn field(&self, name: &str) -> Option<&str> {
+    let value = match (self, name) {
+      (BlockData::User { username, .. }, "username")               => username,
+      (BlockData::User { display_name, .. }, "display_name")       => display_name,
+      (BlockData::User { biography, .. }, "biography")             => biography,
+      (BlockData::UserUpdate { display_name, .. }, "display_name") => display_name,
+      (BlockData::UserUpdate { biography, .. }, "biography")       => biography,
+      (BlockData::Post { body, .. }, "body")                       => body,
+      _ => return None,
+    };
+    Some(value.as_str())
+  }
+}
+
+/**
+ * Check that a username is 3 to 30 ASCII letters, digits or underscores.
+ * Usernames are unique regardless of case.
+ */
+pub fn is_username(username: &str) -> bool {
+  (3..=30).contains(&username.len())
+    && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Rules {
+  /**
+   * Validate the fields of a transaction against the limits.
+   */
+  pub fn validate_limits(&self, data: &BlockData) -> Result<(), String> {
+    for limit in self.limits {
+      let value = data.field(limit.field).unwrap_or_default();
+
+      if text::length(value) > limit.max {
+        return Err(format!("{} exceeds {} characters.", limit.label, limit.max));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_limit_names_a_field() {
+      let samples = [
+        BlockData::Genesis { network: String::new(), accounts: vec![] },
+        BlockData::User {
+          display_name: String::new(),
+          username:     String::new(),
+          biography:    String::new(),
+        },
+        BlockData::UserUpdate {
+          display_name: String::new(),
+          biography:    String::new(),
+        },
+        BlockData::Post {
+          body:  String::new(),
+          reply: None,
+        },
+      ];
+
+      for data in samples {
+        for limit in data.rules().limits {
+          assert!(data.field(limit.field).is_some(), "{:?} has no {}", data, limit.field);
+        }
+      }
+    }
+
+    #[test]
+    fn test_update_has_registration_limits() {
+      let update = BlockData::UserUpdate {
+        display_name: "a".repeat(256),
+        biography:    String::new(),
+      };
+
+      assert_eq!(
+        update.rules().validate_limits(&update).unwrap_err(),
+        "Display name exceeds 255 characters."
+      );
+    }
+
+    #[test]
+    fn test_limits_count_graphemes() {
+      let post = BlockData::Post {
+        body:  "👍🏽".repeat(300),
+        reply: None,
+      };
+      assert!(post.rules().validate_limits(&post).is_ok());
+
+      let post = BlockData::Post {
+        body:  "👍🏽".repeat(301),
+        reply: None,
+      };
+      assert!(post.rules().validate_limits(&post).is_err());
+    }
+
+    #[test]
+    fn test_post_work_grows_with_activity_and_length() {
+      assert_eq!(post_work(0, "hello"), 0);
+      assert_eq!(post_work(3, "hello"), 0);
+      assert_eq!(post_work(4, "hello"), 1);
+      assert_eq!(post_work(9, "hello"), 2);
+      assert_eq!(post_work(0, &"a".repeat(200)), 1);
+      assert_eq!(post_work(0, &"👍🏽".repeat(199)), 0);
+    }
+
+    #[test]
+    fn test_username_grammar() {
+      assert!(is_username("hbackman"));
+      assert!(is_username("Bob_1"));
+      assert!(is_username(&"a".repeat(30)));
+
+      assert!(!is_username("ab"));
+      assert!(!is_username(&"a".repeat(31)));
+      assert!(!is_username("bob smith"));
+      assert!(!is_username("bob\u{200b}"));
+      assert!(!is_username("аlice"));
+      assert!(!is_username("bob-1"));
+    }
+}
@@ -1,41 +1,178 @@
-use heed::{EnvOpenOptions, Database};
-use heed::types::SerdeJson;
-use heed::types::U64;
+use heed::{EnvOpenOptions, Database, RwTxn};
+use heed::types::{Bytes, SerdeJson, Str, Unit, U64};
 use heed::Env;
-use byteorder::NativeEndian;
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
+use serde::{Serialize, Deserialize};
 use std::fs;
+use std::ops::Bound;
 use std::path::Path;
-use crate::blockchain::block::Block;
+use tracing::info;
+use crate::blockchain::block::{Block, BlockData};
 
+/**
+ * The consensus state of a registered account.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+  pub username:   String,
+  pub registered: u64,
+}
+
+/**
+ * Block storage and the chain state derived from it. Blocks and state are
+ * written in the same transaction, so the state always matches the top of
+ * the chain.
+ */
 #[derive(Debug, Clone)]
 pub struct Store {
   pub env: Env,
-  pub db: Database<U64<NativeEndian>, SerdeJson<Block>>,
+  // Keys are big endian so that blocks are sorted by height.
+  pub db: Database<U64<BigEndian>, SerdeJson<Block>>,
+  // Accounts by public key.
+  accounts: Database<Str, SerdeJson<Account>>,
+  // Public keys by lowercased username.
+  usernames: Database<Str, Str>,
+  // The heights of the posts of each account, keyed by `post_key`.
+  posts: Database<Bytes, Unit>,
 }
 
 impl Store {
   pub fn new() -> heed::Result<Self> {
-    let path = Path::new("blockchain");
+    Self::open("blockchain")
+  }
+
+  /**
+   * Open the block storage at the given path.
+   */
+  pub fn open<P: AsRef<Path>>(path: P) -> heed::Result<Self> {
+    let path = path.as_ref();
     if !path.exists() {
       fs::create_dir_all(path)?;
     }
 
     let env = unsafe {
       EnvOpenOptions::new()
-        .max_dbs(1)
+        .max_dbs(5)
         .open(path)?
     };
 
-    let db = {
-      let mut wtxn = env.write_txn()?;
-      let db = env.create_database(&mut wtxn, None)?;
-      wtxn.commit()?;
-      db
+    let mut wtxn = env.write_txn()?;
+
+    // Stores from before account state kept the blocks in the unnamed
+    // database, keyed by native endian heights.
+    let legacy = env.open_database::<U64<BigEndian>, SerdeJson<Block>>(&wtxn, Some("blocks"))?.is_none()
+      && !env.open_database::<Bytes, Bytes>(&wtxn, None)?.unwrap().is_empty(&wtxn)?;
+
+    let legacy_blocks = if legacy {
+      Self::take_legacy_blocks(&env, &mut wtxn)?
+    } else {
+      vec![]
     };
 
-    Ok(Self {
-      env, db
-    })
+    // Stores from before posts were counted per account.
+    let uncounted = env.open_database::<Bytes, Unit>(&wtxn, Some("posts"))?.is_none();
+
+    let store = Self {
+      db:        env.create_database(&mut wtxn, Some("blocks"))?,
+      accounts:  env.create_database(&mut wtxn, Some("accounts"))?,
+      usernames: env.create_database(&mut wtxn, Some("usernames"))?,
+      posts:     env.create_database(&mut wtxn, Some("posts"))?,
+      env:       env.clone(),
+    };
+
+    for block in legacy_blocks {
+      store.apply_block(&mut wtxn, &block)?;
+    }
+
+    if uncounted {
+      store.count_existing_posts(&mut wtxn)?;
+    }
+
+    store.lowercase_usernames(&mut wtxn)?;
+
+    wtxn.commit()?;
+
+    Ok(store)
+  }
+
+  /**
+   * Remove the blocks from a legacy store, in chain order.
+   */
+  fn take_legacy_blocks(env: &Env, wtxn: &mut RwTxn) -> heed::Result<Vec<Block>> {
+    let legacy = env.open_database::<Bytes, Bytes>(wtxn, None)?.unwrap();
+
+    let mut blocks = legacy
+      .iter(wtxn)?
+      .map(|res| {
+        let (_, bytes) = res?;
+        serde_json::from_slice::<Block>(bytes).map_err(|e| heed::Error::Decoding(Box::new(e)))
+      })
+      .collect::<heed::Result<Vec<Block>>>()?;
+
+    blocks.sort_by_key(|block| block.index);
+
+    for block in &blocks {
+      let mut key = [0u8; 8];
+      NativeEndian::write_u64(&mut key, block.index);
+      legacy.delete(wtxn, &key)?;
+    }
+
+    info!(blocks = blocks.len(), "Migrated blocks to the new store layout");
+
+    Ok(blocks)
+  }
+
+  /**
+   * Record the posts that are already on the chain.
+   */
+  fn count_existing_posts(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+    let blocks = self.db
+      .iter(wtxn)?
+      .map(|res| res.map(|(_, block)| block))
+      .collect::<heed::Result<Vec<Block>>>()?;
+
+    for block in blocks {
+      if let BlockData::Post { .. } = block.data {
+        self.posts.put(wtxn, &post_key(&block.public_key, block.index), &())?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Key usernames by their lowercase form. Stores from before usernames were
+   * case-insensitive kept them as registered.
+   */
+  fn lowercase_usernames(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+    let mixed = self.usernames
+      .iter(wtxn)?
+      .map(|res| res.map(|(username, key)| (username.to_string(), key.to_string())))
+      .collect::<heed::Result<Vec<(String, String)>>>()?
+      .into_iter()
+      .filter(|(username, _)| *username != username.to_lowercase())
+      .collect::<Vec<_>>();
+
+    for (username, public_key) in mixed {
+      self.usernames.delete(wtxn, &username)?;
+
+      let lowercase = username.to_lowercase();
+      let registered = |wtxn: &RwTxn, key: &str| -> heed::Result<u64> {
+        Ok(self.accounts.get(wtxn, key)?.map_or(u64::MAX, |a| a.registered))
+      };
+
+      // The first registration of a username keeps it.
+      let keep = match self.usernames.get(wtxn, &lowercase)? {
+        Some(owner) => registered(wtxn, &public_key)? < registered(wtxn, owner)?,
+        None        => true,
+      };
+
+      if keep {
+        self.usernames.put(wtxn, &lowercase, &public_key)?;
+      }
+    }
+
+    Ok(())
   }
 
   /**
@@ -48,26 +185,83 @@ impl Store {
   }
 
   /**
-   * Persist a block into storage.
+   * Persist a block into storage and apply it to the chain state.
    */
   pub fn put_block(&self, block: Block) -> heed::Result<()> {
     let mut wtxn = self.env.write_txn()?;
-    self.db.put(&mut wtxn, &block.index, &block)?;
+    self.apply_block(&mut wtxn, &block)?;
     wtxn.commit()?;
     Ok(())
   }
 
+  fn apply_block(&self, wtxn: &mut RwTxn, block: &Block) -> heed::Result<()> {
+    self.db.put(wtxn, &block.index, block)?;
+
+    match &block.data {
+      BlockData::User { username, .. } => {
+        self.register(wtxn, &block.public_key, username, block.index)?;
+      },
+      BlockData::Genesis { accounts, .. } => {
+        for account in accounts {
+          self.register(wtxn, &account.public_key, &account.username, block.index)?;
+        }
+      },
+      BlockData::Post { .. } => {
+        self.posts.put(wtxn, &post_key(&block.public_key, block.index), &())?;
+      },
+      _ => {}
+    }
+
+    Ok(())
+  }
+
+  fn register(&self, wtxn: &mut RwTxn, public_key: &str, username: &str, index: u64) -> heed::Result<()> {
+    self.accounts.put(wtxn, public_key, &Account {
+      username:   username.to_string(),
+      registered: index,
+    })?;
+    self.usernames.put(wtxn, &username.to_lowercase(), public_key)
+  }
+
+  /**
+   * Remove the block on the top of the chain and revert its changes to the
+   * chain state.
+   */
+  pub fn pop_block(&self) -> heed::Result<Option<Block>> {
+    let mut wtxn = self.env.write_txn()?;
+
+    let Some((index, block)) = self.db.last(&wtxn)? else {
+      return Ok(None);
+    };
+
+    self.db.delete(&mut wtxn, &index)?;
+
+    match &block.data {
+      BlockData::User { username, .. } => {
+        self.accounts.delete(&mut wtxn, &block.public_key)?;
+        self.usernames.delete(&mut wtxn, &username.to_lowercase())?;
+      },
+      BlockData::Post { .. } => {
+        self.posts.delete(&mut wtxn, &post_key(&block.public_key, block.index))?;
+      },
+      _ => {}
+    }
+
+    wtxn.commit()?;
+
+    Ok(Some(block))
+  }
+
   /**
    * Retrieve the block on the top of the chain.
    */
   pub fn top_block(&self) -> heed::Result<Block> {
     let rtxn = self.env.read_txn()?;
-    let iter = self.db.iter(&rtxn)?;
 
-    iter
-      .map(|res| res.map(|(_, block)| block))
-      .last()
-      .unwrap()
+    Ok(self.db
+      .last(&rtxn)?
+      .map(|(_, block)| block)
+      .unwrap())
   }
 
   /**
@@ -76,4 +270,191 @@ impl Store {
   pub fn get_height(&self) -> heed::Result<u64> {
     Ok(self.top_block()?.index)
   }
+
+  /**
+   * Retrieve the account registered with a public key.
+   */
+  pub fn get_account(&self, public_key: &str) -> heed::Result<Option<Account>> {
+    let rtxn = self.env.read_txn()?;
+    let account = self.accounts.get(&rtxn, public_key)?;
+    Ok(account)
+  }
+
+  /**
+   * Retrieve the public key that registered a username, ignoring case.
+   */
+  pub fn get_username_owner(&self, username: &str) -> heed::Result<Option<String>> {
+    let rtxn = self.env.read_txn()?;
+    let public_key = self.usernames
+      .get(&rtxn, &username.to_lowercase())?
+      .map(str::to_string);
+    Ok(public_key)
+  }
+
+  /**
+   * Count the posts of an account in the blocks from `from` up to but not
+   * including `to`.
+   */
+  pub fn count_posts(&self, public_key: &str, from: u64, to: u64) -> heed::Result<usize> {
+    let rtxn = self.env.read_txn()?;
+
+    let from = post_key(public_key, from);
+    let to   = post_key(public_key, to);
+
+    let mut count = 0;
+    for res in self.posts.range(&rtxn, &(Bound::Included(&from[..]), Bound::Excluded(&to[..])))? {
+      res?;
+      count += 1;
+    }
+
+    Ok(count)
+  }
+}
+
+/**
+ * The key of a post, which sorts the posts of an account by height.
+ */
+fn post_key(public_key: &str, height: u64) -> Vec<u8> {
+  let mut key = public_key.as_bytes().to_vec();
+  key.push(0);
+  key.extend_from_slice(&height.to_be_bytes());
+  key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::temp_dir;
+
+    fn user_block(index: u64, username: &str) -> Block {
+      let mut block = Block::new(BlockData::User {
+        display_name: username.to_string(),
+        username:     username.to_string(),
+        biography:    "".to_string(),
+      }, index, "0".to_string());
+
+      block.public_key = format!("key-{}", username);
+      block
+    }
+
+    fn post_block(index: u64, username: &str) -> Block {
+      let mut block = Block::new(BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      }, index, "0".to_string());
+
+      block.public_key = format!("key-{}", username);
+      block
+    }
+
+    #[test]
+    fn test_blocks_are_ordered_by_height() {
+      let store = Store::open(temp_dir()).unwrap();
+
+      for index in 0..300 {
+        store.put_block(Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, index, "0".to_string())).unwrap();
+      }
+
+      assert_eq!(store.get_height().unwrap(), 299);
+    }
+
+    #[test]
+    fn test_account_state_follows_blocks() {
+      let store = Store::open(temp_dir()).unwrap();
+
+      store.put_block(Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 0, "0".to_string())).unwrap();
+      store.put_block(user_block(1, "alice")).unwrap();
+
+      assert_eq!(store.get_account("key-alice").unwrap(), Some(Account {
+        username:   "alice".to_string(),
+        registered: 1,
+      }));
+      assert_eq!(store.get_username_owner("alice").unwrap(), Some("key-alice".to_string()));
+      assert_eq!(store.get_username_owner("ALICE").unwrap(), Some("key-alice".to_string()));
+
+      assert_eq!(store.pop_block().unwrap().unwrap().index, 1);
+
+      assert_eq!(store.get_account("key-alice").unwrap(), None);
+      assert_eq!(store.get_username_owner("alice").unwrap(), None);
+      assert_eq!(store.get_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_posts_are_counted_per_account() {
+      let store = Store::open(temp_dir()).unwrap();
+
+      store.put_block(Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 0, "0".to_string())).unwrap();
+      for index in 1..=4 {
+        store.put_block(post_block(index, if index == 3 { "bob" } else { "alice" })).unwrap();
+      }
+
+      assert_eq!(store.count_posts("key-alice", 0, 5).unwrap(), 3);
+      assert_eq!(store.count_posts("key-alice", 2, 4).unwrap(), 1);
+      assert_eq!(store.count_posts("key-bob", 0, 5).unwrap(), 1);
+
+      store.pop_block().unwrap();
+      assert_eq!(store.count_posts("key-alice", 0, 5).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_older_stores_have_posts_counted() {
+      let dir = temp_dir();
+
+      let env = unsafe { EnvOpenOptions::new().max_dbs(3).open(&dir).unwrap() };
+      let mut wtxn = env.write_txn().unwrap();
+      let db: Database<U64<BigEndian>, SerdeJson<Block>> = env.create_database(&mut wtxn, Some("blocks")).unwrap();
+
+      db.put(&mut wtxn, &0, &Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 0, "0".to_string())).unwrap();
+      db.put(&mut wtxn, &1, &post_block(1, "alice")).unwrap();
+      db.put(&mut wtxn, &2, &post_block(2, "alice")).unwrap();
+
+      wtxn.commit().unwrap();
+      env.prepare_for_closing().wait();
+
+      let store = Store::open(&dir).unwrap();
+
+      assert_eq!(store.count_posts("key-alice", 0, 3).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mixed_case_usernames_are_lowercased() {
+      let dir = temp_dir();
+      let store = Store::open(&dir).unwrap();
+
+      store.put_block(Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 0, "0".to_string())).unwrap();
+      store.put_block(user_block(1, "Alice")).unwrap();
+
+      // Re-key the username the way older stores kept it.
+      let mut wtxn = store.env.write_txn().unwrap();
+      store.usernames.delete(&mut wtxn, "alice").unwrap();
+      store.usernames.put(&mut wtxn, "Alice", "key-Alice").unwrap();
+      wtxn.commit().unwrap();
+
+      let store = Store::open(&dir).unwrap();
+
+      assert_eq!(store.get_username_owner("aLiCe").unwrap(), Some("key-Alice".to_string()));
+    }
+
+    #[test]
+    fn test_legacy_store_is_migrated() {
+      let dir = temp_dir();
+
+      let env = unsafe { EnvOpenOptions::new().max_dbs(1).open(&dir).unwrap() };
+      let mut wtxn = env.write_txn().unwrap();
+      let db: Database<U64<NativeEndian>, SerdeJson<Block>> = env.create_database(&mut wtxn, None).unwrap();
+
+      db.put(&mut wtxn, &0, &Block::new(BlockData::Genesis { network: "devnet".to_string(), accounts: vec![] }, 0, "0".to_string())).unwrap();
+      for index in 1..=300 {
+        db.put(&mut wtxn, &index, &user_block(index, &format!("user{}", index))).unwrap();
+      }
+
+      wtxn.commit().unwrap();
+      env.prepare_for_closing().wait();
+
+      let store = Store::open(&dir).unwrap();
+
+      assert_eq!(store.get_height().unwrap(), 300);
+      assert_eq!(store.get_block(256).unwrap().unwrap().index, 256);
+      assert_eq!(store.get_username_owner("user256").unwrap(), Some("key-user256".to_string()));
+    }
 }
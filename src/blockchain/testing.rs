@@ -0,0 +1,69 @@
+use ed25519_dalek::{Signer, SigningKey};
+use crate::blockchain::block::{Block, BlockData, PendingBlock};
+use crate::blockchain::chain::Blockchain;
+
+/**
+ * A keypair for signing test blocks.
+ */
+pub struct Account {
+  key: SigningKey,
+}
+
+impl Account {
+  pub fn new(seed: u8) -> Self {
+    Self {
+      key: SigningKey::from_bytes(&[seed; 32]),
+    }
+  }
+
+  pub fn public_key(&self) -> String {
+    hex::encode(self.key.verifying_key().as_bytes())
+  }
+
+  pub fn sign(&self, data: &BlockData) -> String {
+    hex::encode(self.key.sign(data.to_string_for_signing().as_bytes()).to_bytes())
+  }
+
+  pub fn pending(&self, data: BlockData) -> PendingBlock {
+    PendingBlock::new(data.clone(), self.public_key(), self.sign(&data))
+  }
+
+  /**
+   * Sign and mine a block on top of the chain.
+   */
+  pub fn mine(&self, chain: &Blockchain, data: BlockData) -> Block {
+    let mut block = Block::next(&chain.top_block(), data.clone());
+
+    block.public_key = self.public_key();
+    block.signature  = self.sign(&data);
+    block.mine_block(chain.difficulty(&block).unwrap());
+    block
+  }
+
+  /**
+   * Register the account on the chain.
+   */
+  pub fn register(&self, chain: &mut Blockchain, username: &str) -> Block {
+    let block = self.mine(chain, BlockData::User {
+      display_name: username.to_string(),
+      username:     username.to_string(),
+      biography:    "".to_string(),
+    });
+
+    chain.add_block(block.clone()).unwrap();
+    block
+  }
+
+  /**
+   * Post on the chain.
+   */
+  pub fn post(&self, chain: &mut Blockchain, body: &str, reply: Option<&str>) -> Result<Block, String> {
+    let block = self.mine(chain, BlockData::Post {
+      body:  body.to_string(),
+      reply: reply.map(str::to_string),
+    });
+
+    chain.add_block(block.clone())?;
+    Ok(block)
+  }
+}
@@ -0,0 +1,95 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/**
+ * The length of a text as a reader sees it, in grapheme clusters. An emoji
+ * with modifiers or a letter with combining accents counts as one.
+ */
+pub fn length(text: &str) -> usize {
+  text.graphemes(true).count()
+}
+
+/**
+ * Extract the `#hashtags` in a post body. Tags are lowercased and returned
+ * once each, in the order they first appear. A tag has to start at a word
+ * boundary and can't be only digits, so `issue#4` and `#1` are not tags.
+ */
+pub fn hashtags(body: &str) -> Vec<String> {
+  extract(body, '#', char::is_alphanumeric)
+    .into_iter()
+    .filter(|tag| !tag.chars().all(|c| c.is_ascii_digit() || c == '_'))
+    .collect()
+}
+
+/**
+ * Extract the `@username` mentions in a post body. Mentions are lowercased
+ * and returned once each. A mention has to start at a word boundary, so email
+ * addresses are not mentions.
+ */
+pub fn mentions(body: &str) -> Vec<String> {
+  extract(body, '@', |c| c.is_ascii_alphanumeric())
+}
+
+fn extract(body: &str, sigil: char, is_word: fn(char) -> bool) -> Vec<String> {
+  let is_word_char = |c: char| is_word(c) || c == '_';
+
+  let mut found: Vec<String> = vec![];
+  let mut prev: Option<char> = None;
+  let mut chars = body.char_indices().peekable();
+
+  while let Some((i, c)) = chars.next() {
+    let at_boundary = prev.is_none_or(|p| !p.is_alphanumeric() && p != '_' && p != sigil);
+    prev = Some(c);
+
+    if c != sigil || !at_boundary {
+      continue;
+    }
+
+    let start = i + c.len_utf8();
+    let mut end = start;
+
+    while let Some(&(j, next)) = chars.peek() {
+      if !is_word_char(next) {
+        break;
+      }
+      end = j + next.len_utf8();
+      prev = Some(next);
+      chars.next();
+    }
+
+    let word = body[start..end].to_lowercase();
+
+    if !word.is_empty() && !found.contains(&word) {
+      found.push(word);
+    }
+  }
+
+  found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counts_graphemes() {
+      assert_eq!(length("hello"), 5);
+      assert_eq!(length("café"), 4);
+      assert_eq!(length("cafe\u{301}"), 4);
+      assert_eq!(length("👍🏽👨‍👩‍👧"), 2);
+    }
+
+    #[test]
+    fn test_hashtags() {
+      assert_eq!(hashtags("#Rust and #rust and #blockchain_dev!"), vec!["rust", "blockchain_dev"]);
+      assert_eq!(hashtags("#café, #日本"), vec!["café", "日本"]);
+      assert_eq!(hashtags("issue#4 #1 #2024 # ##double"), Vec::<String>::new());
+      assert_eq!(hashtags("(#tag)"), vec!["tag"]);
+    }
+
+    #[test]
+    fn test_mentions() {
+      assert_eq!(mentions("hi @Alice and @bob_1, cc @alice"), vec!["alice", "bob_1"]);
+      assert_eq!(mentions("mail me at me@example.com"), Vec::<String>::new());
+      assert_eq!(mentions("@ @@bob @ålice"), Vec::<String>::new());
+    }
+}
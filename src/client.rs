@@ -0,0 +1,138 @@
+use clap::{Arg, ArgMatches, Command};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use std::process;
+
+/**
+ * The `cli` subcommand, which controls a running node over the admin API.
+ */
+pub fn cli() -> Command {
+  Command::new("cli")
+    .about("Control a running node through its admin API")
+    .subcommand_required(true)
+    .args([
+      Arg::new("api")
+        .long("api")
+        .help("The API of the node")
+        .default_value("http://127.0.0.1:3030"),
+      Arg::new("token")
+        .long("token")
+        .help("The admin token, defaults to the one in config.toml"),
+    ])
+    .subcommands([
+      Command::new("send")
+        .about("Broadcast a message to all peers")
+        .arg(Arg::new("message").required(true).num_args(1..)),
+      Command::new("connect")
+        .about("Connect to a peer")
+        .arg(Arg::new("addr").help("The IP:PORT of the peer").required(true)),
+      Command::new("peers")
+        .about("List connected peers"),
+      Command::new("sync")
+        .about("Sync the blockchain"),
+      Command::new("chain")
+        .about("List the blockchain contents")
+        .args([
+          Arg::new("from")
+            .long("from")
+            .help("The first block to list")
+            .value_parser(clap::value_parser!(u64)),
+          Arg::new("limit")
+            .long("limit")
+            .help("The most blocks to list")
+            .value_parser(clap::value_parser!(u64)),
+        ]),
+    ])
+}
+
+/**
+ * Run a command against the node and print the reply. Exits with an error
+ * when the node can't be reached or refuses the command.
+ */
+pub async fn run(cli: &ArgMatches, token: Option<String>) {
+  let api = cli.get_one::<String>("api").unwrap().trim_end_matches('/');
+
+  let Some(token) = cli.get_one::<String>("token").cloned().or(token) else {
+    eprintln!("No admin token, pass --token or set admin_token in config.toml.");
+    process::exit(1);
+  };
+
+  let client = Client::new();
+  let url = |path: &str| format!("{}/admin/{}", api, path);
+
+  let request = match cli.subcommand() {
+    Some(("send", args)) => {
+      let message = args
+        .get_many::<String>("message")
+        .unwrap()
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+
+      with_json(client.post(url("chat")), json!({ "message": message }))
+    },
+    Some(("connect", args)) => {
+      let addr = args.get_one::<String>("addr").unwrap();
+
+      with_json(client.post(url("peers")), json!({ "addr": addr }))
+    },
+    Some(("peers", _)) => client.get(url("peers")),
+    Some(("sync", _)) => client.post(url("sync")),
+    Some(("chain", args)) => {
+      let mut query = vec![];
+
+      if let Some(from) = args.get_one::<u64>("from") {
+        query.push(("from", from));
+      }
+      if let Some(limit) = args.get_one::<u64>("limit") {
+        query.push(("limit", limit));
+      }
+
+      client.get(url("chain")).query(&query)
+    },
+    _ => unreachable!("a subcommand is required"),
+  };
+
+  if let Err(e) = send(request.bearer_auth(token)).await {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+}
+
+fn with_json(request: RequestBuilder, body: Value) -> RequestBuilder {
+  request
+    .header(CONTENT_TYPE, "application/json")
+    .body(body.to_string())
+}
+
+/**
+ * Send a request and print the reply, pretty printed when it is JSON.
+ */
+async fn send(request: RequestBuilder) -> Result<(), String> {
+  let response = request
+    .send()
+    .await
+    .map_err(|e| format!("Could not reach the node: {}", e))?;
+
+  let status = response.status();
+  let body = response
+    .text()
+    .await
+    .map_err(|e| format!("Could not read the reply: {}", e))?;
+
+  let body = match serde_json::from_str::<Value>(&body) {
+    Ok(json) => serde_json::to_string_pretty(&json).unwrap_or(body),
+    Err(_)   => body,
+  };
+
+  if !status.is_success() {
+    return Err(format!("The node replied {}: {}", status, body));
+  }
+
+  if !body.is_empty() {
+    println!("{}", body);
+  }
+
+  Ok(())
+}
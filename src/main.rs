@@ -1,31 +1,170 @@
 pub mod api;
 pub mod p2p;
 pub mod blockchain;
+pub mod metrics;
+mod client;
 
-use toml;
 use std::fs;
+use std::path::PathBuf;
+use std::process;
 use std::error::Error;
 use serde::Deserialize;
 use clap::{Arg, ArgMatches, Command};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 use blockchain::chain::Blockchain;
-use p2p::p2p::start_p2p;
+use blockchain::genesis::Genesis;
+use blockchain::handle::ChainHandle;
+use blockchain::miner::Miner;
+use p2p::node::Node;
+use p2p::p2p::{start_p2p, P2pConfig};
 use api::api::start_api;
 
 #[derive(Debug, Deserialize)]
 struct Config {
-  peers: Vec<String>,
+  #[serde(default)]
+  peers:         Vec<String>,
+  // One of the built-in networks.
+  network:       Option<String>,
+  // A custom network, instead of a built-in one.
+  genesis:       Option<Genesis>,
+  // The bearer token for the admin API, which is off without one.
+  admin_token:   Option<String>,
+  // Find peers on the local network.
+  #[serde(default)]
+  lan_discovery: bool,
 }
 
 #[tokio::main]
 async fn main() {
   let matches = cli().get_matches();
   let config = get_config().unwrap();
-  let chain = Blockchain::new_arc();
 
-  tokio::join!(
-    start_p2p(chain.clone(), get_p2p_addr(matches.clone()), config.peers),
-    start_api(chain.clone(), get_api_addr(matches.clone())),
-  );
+  if let Some(("cli", matches)) = matches.subcommand() {
+    client::run(matches, config.admin_token).await;
+    return;
+  }
+
+  init_logging(&matches).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    process::exit(1);
+  });
+
+  let genesis = get_genesis(&matches, &config).unwrap_or_else(|e| {
+    error!("{}", e);
+    process::exit(1);
+  });
+
+  let chain = Blockchain::open(&get_data_dir(&genesis), &genesis).unwrap_or_else(|e| {
+    error!("{}", e);
+    process::exit(1);
+  });
+
+  info!(network = %genesis.network, genesis = %chain.genesis_hash(), "Running");
+
+  let chain = ChainHandle::start(chain);
+  let miner = Arc::new(Miner::new(get_mining_threads(matches.clone())));
+
+  let p2p = P2pConfig {
+    addr:         get_p2p_addr(matches.clone()),
+    peers:        config.peers,
+    address_book: get_data_dir(&genesis).join("peers.json"),
+    bans:         get_data_dir(&genesis).join("bans.json"),
+    outbound:     *matches.get_one::<usize>("outbound-peers").unwrap(),
+    max_inbound:  *matches.get_one::<usize>("max-inbound-peers").unwrap(),
+    max_per_ip:   *matches.get_one::<usize>("max-peers-per-ip").unwrap(),
+    lan:          matches.get_flag("lan-discovery") || config.lan_discovery,
+    console:      !matches.get_flag("daemon"),
+  };
+
+  if !p2p.console && config.admin_token.is_none() {
+    warn!("Running without a console or an admin token, so the node can't be controlled");
+  }
+
+  let node = Arc::new(Node::new(chain.clone(), miner, &p2p).await);
+  let shutdown_timeout = Duration::from_secs(*matches.get_one::<u64>("shutdown-timeout").unwrap());
+
+  let running = tokio::spawn({
+    let node = node.clone();
+
+    async move {
+      tokio::join!(
+        start_p2p(node.clone(), p2p),
+        start_api(chain.clone(), node, get_api_addr(matches.clone()), config.admin_token),
+      );
+    }
+  });
+
+  shutdown_signal().await;
+  info!(timeout = shutdown_timeout.as_secs(), "Shutting down");
+  node.stop();
+
+  let stopped = tokio::time::timeout(shutdown_timeout, async {
+    let _ = running.await;
+    node.chain.close().await
+  }).await;
+
+  // Reading the console blocks a thread that would keep the process alive,
+  // so exit instead of returning.
+  match stopped {
+    Ok(Ok(())) => {
+      info!("Shut down cleanly");
+      process::exit(0);
+    },
+    Ok(Err(e)) => {
+      error!(error = %e, "Could not close the chain");
+      process::exit(1);
+    },
+    Err(_) => {
+      error!(timeout = shutdown_timeout.as_secs(), "Shutdown timed out");
+      process::exit(1);
+    },
+  }
+}
+
+/**
+ * Wait for Ctrl-C, or for SIGTERM where there is one.
+ */
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {},
+      _ = terminate.recv() => {},
+    }
+  }
+
+  #[cfg(not(unix))]
+  let _ = tokio::signal::ctrl_c().await;
+}
+
+/**
+ * Log at the level given on the command line, or else in `RUST_LOG`, as
+ * lines for people to read or as JSON.
+ */
+fn init_logging(cli: &ArgMatches) -> Result<(), String> {
+  let filter = cli.get_one::<String>("log-level")
+    .cloned()
+    .or_else(|| std::env::var("RUST_LOG").ok())
+    .unwrap_or_else(|| "info".to_string());
+
+  let filter = EnvFilter::try_new(&filter)
+    .map_err(|e| format!("Invalid log level '{}': {}", filter, e))?;
+
+  let logger = tracing_subscriber::fmt().with_env_filter(filter);
+
+  match cli.get_one::<String>("log-format").map(String::as_str) {
+    Some("json") => logger.json().init(),
+    _            => logger.init(),
+  }
+
+  Ok(())
 }
 
 fn get_p2p_addr(cli: ArgMatches) -> String {
@@ -36,6 +175,39 @@ fn get_api_addr(cli: ArgMatches) -> String {
   format!("0.0.0.0:{}", cli.get_one::<String>("api-port").unwrap())
 }
 
+fn get_mining_threads(cli: ArgMatches) -> usize {
+  cli.get_one::<usize>("mining-threads")
+    .copied()
+    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+fn get_genesis(cli: &ArgMatches, config: &Config) -> Result<Genesis, String> {
+  let network = cli.get_one::<String>("network")
+    .or(config.network.as_ref());
+
+  match (&config.genesis, network) {
+    (Some(genesis), Some(network)) if *network != genesis.network => {
+      Err(format!("The configured genesis is for {}, not {}.", genesis.network, network))
+    },
+    (Some(genesis), _) => Ok(genesis.clone()),
+    (None, network) => {
+      let network = network.map_or("mainnet", String::as_str);
+      Genesis::profile(network).ok_or_else(|| format!("Unknown network '{}'.", network))
+    },
+  }
+}
+
+/**
+ * The directory the chain of a network is kept in. Mainnet uses the working
+ * directory, as nodes did before there were other networks.
+ */
+fn get_data_dir(genesis: &Genesis) -> PathBuf {
+  match genesis.network.as_str() {
+    "mainnet" => PathBuf::from("."),
+    network   => PathBuf::from(network),
+  }
+}
+
 fn get_config() -> Result<Config, Box<dyn Error>> {
   match fs::read_to_string("config.toml") {
     Ok(content) => {
@@ -44,7 +216,11 @@ fn get_config() -> Result<Config, Box<dyn Error>> {
     },
     Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
       Ok(Config {
-        peers: vec![],
+        peers:         vec![],
+        network:       None,
+        genesis:       None,
+        admin_token:   None,
+        lan_discovery: false,
       })
     },
     Err(e) => Err(Box::new(e)),
@@ -66,5 +242,58 @@ fn cli() -> Command {
         .help("The API port")
         .default_value("3030")
         .required(false),
+      Arg::new("network")
+        .long("network")
+        .help("The network to join: mainnet, testnet or devnet")
+        .required(false),
+      Arg::new("mining-threads")
+        .long("mining-threads")
+        .help("The number of mining threads, defaults to one per CPU")
+        .value_parser(clap::value_parser!(usize))
+        .required(false),
+      Arg::new("outbound-peers")
+        .long("outbound-peers")
+        .help("The number of peers to keep outbound connections to, and the most")
+        .value_parser(clap::value_parser!(usize))
+        .default_value("8")
+        .required(false),
+      Arg::new("max-inbound-peers")
+        .long("max-inbound-peers")
+        .help("The most peers to accept connections from")
+        .value_parser(clap::value_parser!(usize))
+        .default_value("32")
+        .required(false),
+      Arg::new("max-peers-per-ip")
+        .long("max-peers-per-ip")
+        .help("The most connections to allow from one address")
+        .value_parser(clap::value_parser!(usize))
+        .default_value("4")
+        .required(false),
+      Arg::new("lan-discovery")
+        .long("lan-discovery")
+        .help("Find peers on the local network over UDP multicast")
+        .action(clap::ArgAction::SetTrue),
+      Arg::new("shutdown-timeout")
+        .long("shutdown-timeout")
+        .help("The seconds to wait for the node to shut down cleanly")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("10")
+        .required(false),
+      Arg::new("log-level")
+        .long("log-level")
+        .help("The level to log at, such as debug or info,cryptogram::p2p=debug, defaults to RUST_LOG or info")
+        .required(false),
+      Arg::new("log-format")
+        .long("log-format")
+        .help("How to write the log")
+        .value_parser(["pretty", "json"])
+        .default_value("pretty")
+        .required(false),
+      Arg::new("daemon")
+        .long("daemon")
+        .visible_alias("no-console")
+        .help("Run without reading commands from stdin")
+        .action(clap::ArgAction::SetTrue),
     ])
+    .subcommand(client::cli())
 }
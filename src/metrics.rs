@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// The upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/**
+ * The metrics of the running node.
+ */
+pub fn metrics() -> &'static Metrics {
+  &METRICS
+}
+
+/**
+ * Counters and latencies collected as the node runs. What can be read off
+ * the node at any time, like the chain height, is left to the scrape.
+ */
+#[derive(Debug, Default)]
+pub struct Metrics {
+  blocks_accepted: AtomicU64,
+  // Rejected blocks by the check they failed.
+  blocks_rejected: Mutex<BTreeMap<&'static str, u64>>,
+  bytes_sent:      AtomicU64,
+  bytes_received:  AtomicU64,
+  // API request latencies by method, route and status.
+  requests:        Mutex<BTreeMap<(String, String, u16), Histogram>>,
+  // Index query latencies by query.
+  queries:         Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+/**
+ * Observations counted into latency buckets.
+ */
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+  buckets: [u64; BUCKETS.len()],
+  sum:     f64,
+  count:   u64,
+}
+
+impl Histogram {
+  fn observe(&mut self, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+
+    if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+      self.buckets[bucket] += 1;
+    }
+
+    self.sum += seconds;
+    self.count += 1;
+  }
+
+  /**
+   * Write the histogram as cumulative buckets, with the labels of the series.
+   */
+  fn render(&self, out: &mut String, name: &str, labels: &str) {
+    let mut cumulative = 0;
+
+    for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+      cumulative += count;
+      let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+    }
+
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count);
+
+    let labels = labels.trim_end_matches(',');
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+  }
+}
+
+/**
+ * Times an index query until it is dropped.
+ */
+pub struct QueryTimer {
+  query: &'static str,
+  start: Instant,
+}
+
+impl Drop for QueryTimer {
+  fn drop(&mut self) {
+    metrics().observe_query(self.query, self.start.elapsed());
+  }
+}
+
+impl Metrics {
+  pub fn block_accepted(&self) {
+    self.blocks_accepted.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn block_rejected(&self, reason: &'static str) {
+    *self.blocks_rejected
+      .lock()
+      .unwrap()
+      .entry(reason)
+      .or_default() += 1;
+  }
+
+  pub fn bytes_sent(&self, bytes: usize) {
+    self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn bytes_received(&self, bytes: usize) {
+    self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+    self.requests
+      .lock()
+      .unwrap()
+      .entry((method.to_string(), route.to_string(), status))
+      .or_default()
+      .observe(elapsed);
+  }
+
+  pub fn observe_query(&self, query: &'static str, elapsed: Duration) {
+    self.queries
+      .lock()
+      .unwrap()
+      .entry(query)
+      .or_default()
+      .observe(elapsed);
+  }
+
+  /**
+   * Time an index query, until the timer is dropped.
+   */
+  pub fn time_query(&self, query: &'static str) -> QueryTimer {
+    QueryTimer {
+      query,
+      start: Instant::now(),
+    }
+  }
+
+  /**
+   * Write the metrics in the Prometheus text format.
+   */
+  pub fn render(&self, out: &mut String) {
+    header(out, "cryptogram_blocks_accepted_total", "counter", "Blocks added to the chain.");
+    let _ = writeln!(out, "cryptogram_blocks_accepted_total {}", self.blocks_accepted.load(Ordering::Relaxed));
+
+    header(out, "cryptogram_blocks_rejected_total", "counter", "Blocks turned away, by the check they failed.");
+    for (reason, count) in self.blocks_rejected.lock().unwrap().iter() {
+      let _ = writeln!(out, "cryptogram_blocks_rejected_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    header(out, "cryptogram_network_bytes_total", "counter", "Bytes exchanged with peers.");
+    let _ = writeln!(out, "cryptogram_network_bytes_total{{direction=\"in\"}} {}", self.bytes_received.load(Ordering::Relaxed));
+    let _ = writeln!(out, "cryptogram_network_bytes_total{{direction=\"out\"}} {}", self.bytes_sent.load(Ordering::Relaxed));
+
+    header(out, "cryptogram_api_request_duration_seconds", "histogram", "API request latency, by route.");
+    for ((method, route, status), histogram) in self.requests.lock().unwrap().iter() {
+      let labels = format!("method=\"{}\",route=\"{}\",status=\"{}\",", method, escape(route), status);
+      histogram.render(out, "cryptogram_api_request_duration_seconds", &labels);
+    }
+
+    header(out, "cryptogram_index_query_duration_seconds", "histogram", "Chain index query latency, by query.");
+    for (query, histogram) in self.queries.lock().unwrap().iter() {
+      let labels = format!("query=\"{}\",", query);
+      histogram.render(out, "cryptogram_index_query_duration_seconds", &labels);
+    }
+  }
+}
+
+/**
+ * Write the help and type lines of a metric.
+ */
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/**
+ * Escape a label value.
+ */
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_rendered() {
+      let metrics = Metrics::default();
+
+      metrics.block_accepted();
+      metrics.block_rejected("work");
+      metrics.block_rejected("work");
+      metrics.bytes_received(100);
+      metrics.observe_request("GET", "/posts/:param", 200, Duration::from_millis(3));
+      metrics.observe_query("get_post", Duration::from_secs(10));
+
+      let mut out = String::new();
+      metrics.render(&mut out);
+
+      assert!(out.contains("cryptogram_blocks_accepted_total 1\n"));
+      assert!(out.contains("cryptogram_blocks_rejected_total{reason=\"work\"} 2\n"));
+      assert!(out.contains("cryptogram_network_bytes_total{direction=\"in\"} 100\n"));
+      assert!(out.contains(
+        "cryptogram_api_request_duration_seconds_bucket{method=\"GET\",route=\"/posts/:param\",status=\"200\",le=\"0.0025\"} 0\n"
+      ));
+      assert!(out.contains(
+        "cryptogram_api_request_duration_seconds_bucket{method=\"GET\",route=\"/posts/:param\",status=\"200\",le=\"0.005\"} 1\n"
+      ));
+      assert!(out.contains("cryptogram_api_request_duration_seconds_count{method=\"GET\",route=\"/posts/:param\",status=\"200\"} 1\n"));
+
+      // Slower than the last bucket, so only in +Inf.
+      assert!(out.contains("cryptogram_index_query_duration_seconds_bucket{query=\"get_post\",le=\"5\"} 0\n"));
+      assert!(out.contains("cryptogram_index_query_duration_seconds_bucket{query=\"get_post\",le=\"+Inf\"} 1\n"));
+    }
+}
@@ -0,0 +1,405 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::seq::SliceRandom;
+
+// The most addresses kept in the book.
+const MAX_ADDRESSES: usize = 1000;
+
+// Seconds to wait before dialing an address again after a failure. Doubles
+// with every failure in a row.
+const RETRY_DELAY: u64 = 60;
+const MAX_RETRY_DELAY: u64 = 24 * 60 * 60;
+
+/**
+ * What the node knows about a dialable peer address.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressEntry {
+  pub addr:         String,
+  // When the address was last connected to or gossiped.
+  pub last_seen:    u64,
+  pub last_attempt: u64,
+  pub successes:    u32,
+  pub failures:     u32,
+  // Failures since the last successful connection.
+  pub streak:       u32,
+}
+
+impl AddressEntry {
+  fn new(addr: String) -> Self {
+    Self {
+      addr,
+      last_seen:    now(),
+      last_attempt: 0,
+      successes:    0,
+      failures:     0,
+      streak:       0,
+    }
+  }
+
+  /**
+   * Check if the address is still waiting out its backoff.
+   */
+  fn backing_off(&self, now: u64) -> bool {
+    if self.streak == 0 {
+      return false;
+    }
+
+    let delay = RETRY_DELAY
+      .saturating_mul(1 << self.streak.min(16))
+      .min(MAX_RETRY_DELAY);
+
+    now < self.last_attempt + delay
+  }
+
+  /**
+   * How much the address is worth dialing. Addresses that worked before come
+   * first, and failures count against them.
+   */
+  fn score(&self) -> i64 {
+    self.successes as i64 * 2 - self.failures as i64 - self.streak as i64 * 4
+  }
+}
+
+/**
+ * The dialable addresses the node has heard of, kept on disk between runs.
+ */
+#[derive(Debug, Default)]
+pub struct AddressBook {
+  path:    Option<PathBuf>,
+  entries: HashMap<String, AddressEntry>,
+}
+
+impl AddressBook {
+  /**
+   * Open the address book at the given path. A missing or unreadable file
+   * gives an empty book.
+   */
+  pub fn open(path: PathBuf) -> Self {
+    let entries = fs::read_to_string(&path)
+      .ok()
+      .and_then(|json| serde_json::from_str::<Vec<AddressEntry>>(&json).ok())
+      .unwrap_or_default()
+      .into_iter()
+      .map(|entry| (entry.addr.clone(), entry))
+      .collect();
+
+    Self {
+      path: Some(path),
+      entries,
+    }
+  }
+
+  /**
+   * Write the address book to disk.
+   */
+  pub fn save(&self) -> io::Result<()> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+
+    let mut entries: Vec<&AddressEntry> = self.entries.values().collect();
+    entries.sort_by(|a, b| a.addr.cmp(&b.addr));
+
+    // Write next to the book and move it over, so a crash can't leave half a
+    // file behind.
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&entries)?)?;
+    fs::rename(tmp, path)
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn get(&self, addr: &str) -> Option<&AddressEntry> {
+    self.entries.get(addr)
+  }
+
+  /**
+   * Add an address, or mark a known one as seen. Returns false when the
+   * address can't be dialed.
+   */
+  pub fn add(&mut self, addr: &str) -> bool {
+    let Some(addr) = dialable(addr) else {
+      return false;
+    };
+
+    if let Some(entry) = self.entries.get_mut(&addr) {
+      entry.last_seen = now();
+      return true;
+    }
+
+    if self.entries.len() >= MAX_ADDRESSES {
+      self.evict();
+    }
+
+    self.entries.insert(addr.clone(), AddressEntry::new(addr));
+    true
+  }
+
+  /**
+   * Add the addresses gossiped by a peer, of which at most `limit` can be new
+   * to the book. Addresses on the loopback or a link-local network only make
+   * sense to the peer, so they are left out, unless the peer is on the same
+   * host. Returns the number of new addresses.
+   */
+  pub fn add_gossiped(&mut self, addrs: &[String], from: IpAddr, limit: usize) -> usize {
+    let mut added = 0;
+
+    for addr in addrs {
+      let Some(addr) = dialable(addr) else {
+        continue;
+      };
+
+      let ip = addr.parse::<SocketAddr>().unwrap().ip();
+      if is_link_local(ip) || (ip.is_loopback() && !from.is_loopback()) {
+        continue;
+      }
+
+      if !self.entries.contains_key(&addr) {
+        if added == limit {
+          continue;
+        }
+        added += 1;
+      }
+
+      self.add(&addr);
+    }
+
+    added
+  }
+
+  /**
+   * Forget an address.
+   */
+  pub fn remove(&mut self, addr: &str) {
+    self.entries.remove(addr);
+  }
+
+  /**
+   * Record a successful connection.
+   */
+  pub fn record_success(&mut self, addr: &str) {
+    if !self.add(addr) {
+      return;
+    }
+
+    if let Some(entry) = self.entries.get_mut(addr) {
+      entry.last_attempt = now();
+      entry.successes += 1;
+      entry.streak = 0;
+    }
+  }
+
+  /**
+   * Record a failed connection attempt.
+   */
+  pub fn record_failure(&mut self, addr: &str) {
+    if let Some(entry) = self.entries.get_mut(addr) {
+      entry.last_attempt = now();
+      entry.failures += 1;
+      entry.streak += 1;
+    }
+  }
+
+  /**
+   * Pick up to `count` addresses to dial, best first, skipping the excluded
+   * ones and those that are backing off after failures.
+   */
+  pub fn candidates(&self, count: usize, exclude: &[String]) -> Vec<String> {
+    let now = now();
+
+    let mut entries: Vec<&AddressEntry> = self.entries
+      .values()
+      .filter(|entry| !exclude.contains(&entry.addr) && !entry.backing_off(now))
+      .collect();
+
+    entries.sort_by(|a, b| b.score().cmp(&a.score()).then(b.last_seen.cmp(&a.last_seen)));
+
+    entries
+      .into_iter()
+      .take(count)
+      .map(|entry| entry.addr.clone())
+      .collect()
+  }
+
+  /**
+   * Pick up to `count` random addresses to share with a peer. Addresses that
+   * keep failing are not passed on.
+   */
+  pub fn sample(&self, count: usize) -> Vec<String> {
+    let entries: Vec<&AddressEntry> = self.entries
+      .values()
+      .filter(|entry| entry.streak < 3)
+      .collect();
+
+    entries
+      .choose_multiple(&mut rand::thread_rng(), count)
+      .map(|entry| entry.addr.clone())
+      .collect()
+  }
+
+  /**
+   * Drop the worst address to make room for a new one.
+   */
+  fn evict(&mut self) {
+    let worst = self.entries
+      .values()
+      .min_by(|a, b| a.score().cmp(&b.score()).then(a.last_seen.cmp(&b.last_seen)))
+      .map(|entry| entry.addr.clone());
+
+    if let Some(worst) = worst {
+      self.entries.remove(&worst);
+    }
+  }
+}
+
+/**
+ * Normalize an address that can be dialed, as `ip:port`.
+ */
+pub fn dialable(addr: &str) -> Option<String> {
+  let addr: SocketAddr = addr.parse().ok()?;
+
+  if addr.ip().is_unspecified() || addr.ip().is_multicast() || addr.port() == 0 {
+    return None;
+  }
+
+  Some(addr.to_string())
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => ip.is_link_local(),
+    IpAddr::V6(ip) => ip.is_unicast_link_local(),
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards")
+    .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_dialable_addresses_are_added() {
+      let mut book = AddressBook::default();
+
+      assert!(book.add("10.0.0.1:5000"));
+      assert!(book.add("[::1]:5000"));
+      assert!(!book.add("0.0.0.0:5000"));
+      assert!(!book.add("10.0.0.1:0"));
+      assert!(!book.add("2f1e1b1c-uuid"));
+      assert!(!book.add("example.com:5000"));
+
+      assert_eq!(book.len(), 2);
+    }
+
+    #[test]
+    fn test_gossip_leaves_out_local_addresses() {
+      let mut book = AddressBook::default();
+      let remote: IpAddr = "10.0.0.9".parse().unwrap();
+
+      let gossip = [
+        "10.0.0.1:5000",
+        "127.0.0.1:5000",
+        "[::1]:5000",
+        "169.254.1.1:5000",
+        "[fe80::1]:5000",
+      ].map(str::to_string);
+
+      assert_eq!(book.add_gossiped(&gossip, remote, 10), 1);
+      assert!(book.get("10.0.0.1:5000").is_some());
+
+      // A peer on the same host can share loopback addresses.
+      assert_eq!(book.add_gossiped(&gossip, "127.0.0.1".parse().unwrap(), 10), 2);
+      assert_eq!(book.len(), 3);
+    }
+
+    #[test]
+    fn test_gossip_adds_a_limited_number_of_new_addresses() {
+      let mut book = AddressBook::default();
+      let remote: IpAddr = "10.0.0.9".parse().unwrap();
+
+      book.add("10.0.0.1:5000");
+
+      let gossip: Vec<String> = (1..=10).map(|i| format!("10.0.0.{}:5000", i)).collect();
+
+      assert_eq!(book.add_gossiped(&gossip, remote, 3), 3);
+      assert_eq!(book.len(), 4);
+    }
+
+    #[test]
+    fn test_candidates_prefer_working_addresses() {
+      let mut book = AddressBook::default();
+
+      book.add("10.0.0.1:5000");
+      book.add("10.0.0.2:5000");
+      book.add("10.0.0.3:5000");
+
+      book.record_success("10.0.0.2:5000");
+      book.record_failure("10.0.0.3:5000");
+
+      // The failed address is backing off.
+      assert_eq!(book.candidates(3, &[]), vec!["10.0.0.2:5000", "10.0.0.1:5000"]);
+      assert_eq!(book.candidates(3, &["10.0.0.2:5000".to_string()]), vec!["10.0.0.1:5000"]);
+    }
+
+    #[test]
+    fn test_backoff_grows_with_failures() {
+      let mut entry = AddressEntry::new("10.0.0.1:5000".to_string());
+      entry.last_attempt = 1000;
+
+      entry.streak = 1;
+      assert!(entry.backing_off(1000 + 119));
+      assert!(!entry.backing_off(1000 + 120));
+
+      entry.streak = 3;
+      assert!(entry.backing_off(1000 + 479));
+      assert!(!entry.backing_off(1000 + 480));
+    }
+
+    #[test]
+    fn test_book_is_persisted() {
+      let path = crate::blockchain::temp_dir().join("peers.json");
+
+      let mut book = AddressBook::open(path.clone());
+      book.add("10.0.0.1:5000");
+      book.record_success("10.0.0.1:5000");
+      book.save().unwrap();
+
+      let book = AddressBook::open(path);
+
+      assert_eq!(book.len(), 1);
+      assert_eq!(book.get("10.0.0.1:5000").unwrap().successes, 1);
+    }
+
+    #[test]
+    fn test_full_book_evicts_the_worst_address() {
+      let mut book = AddressBook::default();
+
+      for i in 0..MAX_ADDRESSES {
+        book.add(&format!("10.0.{}.{}:5000", i / 256, i % 256));
+      }
+      book.record_failure("10.0.0.7:5000");
+
+      book.add("10.1.0.1:5000");
+
+      assert_eq!(book.len(), MAX_ADDRESSES);
+      assert!(book.get("10.0.0.7:5000").is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The misbehavior score at which a peer is disconnected and banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
+// Seconds a misbehaving peer stays banned.
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+
+/**
+ * Something a peer did that an honest peer wouldn't.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+  // A block with a signature that doesn't verify.
+  InvalidSignature,
+  // A block with a forged hash, or one that doesn't meet the difficulty.
+  InvalidWork,
+  // A line that isn't JSON.
+  Malformed,
+  // A line longer than any message.
+  Oversized,
+  // A block response to a request that wasn't made.
+  Unsolicited,
+  // A block at or below the height of the chain, or at the genesis index.
+  Stale,
+}
+
+impl Misbehavior {
+  /**
+   * How much the misbehavior adds to the score of the peer.
+   */
+  pub fn score(&self) -> u32 {
+    match self {
+      Misbehavior::InvalidSignature => 50,
+      Misbehavior::InvalidWork      => 50,
+      Misbehavior::Malformed        => 10,
+      Misbehavior::Oversized        => 50,
+      Misbehavior::Unsolicited      => 20,
+      Misbehavior::Stale            => 10,
+    }
+  }
+}
+
+impl fmt::Display for Misbehavior {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let reason = match self {
+      Misbehavior::InvalidSignature => "invalid block signature",
+      Misbehavior::InvalidWork      => "invalid proof of work",
+      Misbehavior::Malformed        => "malformed message",
+      Misbehavior::Oversized        => "oversized message",
+      Misbehavior::Unsolicited      => "unsolicited block response",
+      Misbehavior::Stale            => "stale block",
+    };
+
+    write!(f, "{}", reason)
+  }
+}
+
+/**
+ * A banned address.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+  pub ip:     IpAddr,
+  // When the ban is lifted, in seconds since the epoch.
+  pub until:  u64,
+  pub reason: String,
+}
+
+/**
+ * The addresses the node refuses to talk to, kept on disk between runs.
+ */
+#[derive(Debug, Default)]
+pub struct BanList {
+  path: Option<PathBuf>,
+  bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+  /**
+   * Open the ban list at the given path. A missing or unreadable file gives
+   * an empty list.
+   */
+  pub fn open(path: PathBuf) -> Self {
+    let bans = fs::read_to_string(&path)
+      .ok()
+      .and_then(|json| serde_json::from_str::<Vec<Ban>>(&json).ok())
+      .unwrap_or_default()
+      .into_iter()
+      .map(|ban| (ban.ip, ban))
+      .collect();
+
+    Self {
+      path: Some(path),
+      bans,
+    }
+  }
+
+  /**
+   * Write the active bans to disk.
+   */
+  pub fn save(&self) -> io::Result<()> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&self.list())?)?;
+    fs::rename(tmp, path)
+  }
+
+  /**
+   * Ban an address for the given number of seconds, replacing an earlier ban.
+   */
+  pub fn ban(&mut self, ip: IpAddr, seconds: u64, reason: &str) {
+    self.bans.insert(ip, Ban {
+      ip,
+      until:  now().saturating_add(seconds),
+      reason: reason.to_string(),
+    });
+  }
+
+  /**
+   * Lift the ban on an address. Returns false when it wasn't banned.
+   */
+  pub fn unban(&mut self, ip: &IpAddr) -> bool {
+    self.bans
+      .remove(ip)
+      .is_some_and(|ban| ban.until > now())
+  }
+
+  /**
+   * Check if an address is banned.
+   */
+  pub fn is_banned(&self, ip: &IpAddr) -> bool {
+    self.bans
+      .get(ip)
+      .is_some_and(|ban| ban.until > now())
+  }
+
+  /**
+   * The active bans, soonest lifted first.
+   */
+  pub fn list(&self) -> Vec<Ban> {
+    let now = now();
+
+    let mut bans: Vec<Ban> = self.bans
+      .values()
+      .filter(|ban| ban.until > now)
+      .cloned()
+      .collect();
+
+    bans.sort_by(|a, b| a.until.cmp(&b.until).then(a.ip.cmp(&b.ip)));
+    bans
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards")
+    .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans_expire() {
+      let mut bans = BanList::default();
+      let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+      bans.ban(ip, 0, "testing");
+      assert!(!bans.is_banned(&ip));
+      assert!(bans.list().is_empty());
+
+      bans.ban(ip, 60, "testing");
+      assert!(bans.is_banned(&ip));
+
+      assert!(bans.unban(&ip));
+      assert!(!bans.is_banned(&ip));
+      assert!(!bans.unban(&ip));
+    }
+
+    #[test]
+    fn test_bans_are_persisted() {
+      let path = crate::blockchain::temp_dir().join("bans.json");
+      let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+      let mut bans = BanList::open(path.clone());
+      bans.ban(ip, 60, "invalid block signature");
+      bans.save().unwrap();
+
+      let bans = BanList::open(path);
+
+      assert!(bans.is_banned(&ip));
+      assert_eq!(bans.list()[0].reason, "invalid block signature");
+    }
+}
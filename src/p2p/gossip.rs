@@ -1,9 +1,14 @@
 use tokio::time::{sleep, Duration};
 use std::sync::Arc;
 use rand::seq::SliceRandom;
-use crate::p2p::node::Node;
+use tracing::{info, warn};
+use crate::p2p::node::{Node, GOSSIP_ADDRESSES};
 use crate::p2p::message::MessageData;
 
+/**
+ * Share known addresses with a few random peers, and ask one of them for
+ * theirs.
+ */
 pub async fn handle_peer_gossip(node: Arc<Node>) {
   loop {
     sleep(Duration::from_secs(10)).await; // Gossip every 10 seconds
@@ -20,14 +25,42 @@ pub async fn handle_peer_gossip(node: Arc<Node>) {
       .cloned()
       .collect();
 
-    // Create a gossip message
     let gossip_message = MessageData::PeerGossip {
-      peers: known_peers,
+      peers: node.addrs.lock().await.sample(GOSSIP_ADDRESSES),
     };
 
-    // Send gossip to selected peers
-    for peer in gossip_targets {
-      node.send(&peer, &gossip_message).await;
+    for peer in &gossip_targets {
+      node.send(peer, &gossip_message).await;
     }
+
+    node.send(&gossip_targets[0], &MessageData::PeerDiscovery {}).await;
+  }
+}
+
+/**
+ * Keep up the outbound connections, dialing addresses from the address book
+ * while there are fewer than the target, and save the book.
+ */
+pub async fn handle_outbound_peers(node: Arc<Node>) {
+  loop {
+    let connected = node.get_outbound_addrs().await;
+    let missing = node.outbound.saturating_sub(connected.len());
+
+    let candidates = node.addrs
+      .lock()
+      .await
+      .candidates(missing, &connected);
+
+    for addr in candidates {
+      if let Err(e) = node.connect_to_peer(&addr).await {
+        info!(%addr, error = %e, "Could not connect to a known address");
+      }
+    }
+
+    if let Err(e) = node.addrs.lock().await.save() {
+      warn!(error = %e, "Could not save the address book");
+    }
+
+    sleep(Duration::from_secs(30)).await;
   }
 }
@@ -1,14 +1,30 @@
 use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{info, warn};
 use crate::p2p::node::Node;
 use crate::p2p::message::MessageData;
 
+/**
+ * Run the console commands typed on stdin, until stdin is closed.
+ */
 pub async fn handle_user_input(node: Arc<Node>) {
   let mut reader = BufReader::new(tokio::io::stdin());
 
   loop {
     let mut input = String::new();
-    reader.read_line(&mut input).await.unwrap();
+
+    match reader.read_line(&mut input).await {
+      Ok(0)  => {
+        info!("Console closed, the node keeps running");
+        return;
+      },
+      Ok(_)  => {},
+      Err(e) => {
+        warn!(error = %e, "Could not read the console");
+        return;
+      },
+    }
+
     let input = input.trim().to_string();
 
     match input.split_whitespace().collect::<Vec<&str>>().as_slice() {
@@ -45,34 +61,29 @@ pub async fn handle_user_input(node: Arc<Node>) {
  * Handle connecting to a peer.
  */
 async fn handle_peer_connect(node: Arc<Node>, peer: &str) {
-  let peer = peer.to_string();
-
-  println!("Connecting to {}", peer);
-
-  let peer_id = node.connect_to_peer(&peer).await.unwrap();
+  info!(addr = peer, "Connecting");
 
-  // Ask peer for its peers and blockchain.
-  let chain_at = node.chain
-    .lock()
-    .await
-    .len();
-
-  node.send(&peer_id, &MessageData::PeerDiscovery {}).await;
-  node.send(&peer_id, &MessageData::BlockRequest {index: chain_at + 1}).await;
+  if let Err(e) = node.join(peer).await {
+    warn!(addr = peer, error = %e, "Could not connect");
+  }
 }
 
 /**
  * Handle listing connected peers.
  */
 async fn handle_peer_listing(node: Arc<Node>) {
-  let peers = node.get_peers().await;
+  let peers = node.get_peer_infos().await;
 
   if peers.is_empty() {
     println!("No connected peers.");
   } else {
     println!("Connected peers:");
     for peer in peers.iter() {
-      println!("- {}", peer);
+      println!(
+        "- {} {:?} {} ({}, height {}, score {}, {} bytes sent, {} bytes received, {} dropped)",
+        peer.peer_id, peer.direction, peer.remote, peer.software, peer.tip_height, peer.score,
+        peer.traffic.bytes_sent, peer.traffic.bytes_received, peer.traffic.dropped,
+      );
     }
   }
 }
@@ -82,8 +93,7 @@ async fn handle_peer_listing(node: Arc<Node>) {
  */
 async fn handle_chain_listing(node: Arc<Node>) {
   node.chain
-    .lock()
-    .await
+    .reader()
     .print_chain();
 }
 
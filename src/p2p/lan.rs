@@ -0,0 +1,170 @@
+use serde::{Serialize, Deserialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+use crate::p2p::node::Node;
+
+// The multicast group and port nodes announce themselves on. The group is in
+// the organization-local scope, so announcements stay on the local network.
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const PORT: u16 = 45999;
+
+// How often the node announces itself.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+ * What a node tells the local network about itself.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+  pub node_id: String,
+  pub network: String,
+  // The hash of the genesis block, which tells networks of the same name
+  // apart.
+  pub genesis: String,
+  // The port the node accepts peer connections on.
+  pub port:    u16,
+}
+
+/**
+ * Announce the node on the local network, and connect to the nodes it hears
+ * announced.
+ */
+pub async fn handle_lan_discovery(node: Arc<Node>) {
+  let socket = match bind() {
+    Ok(socket) => Arc::new(socket),
+    Err(e)     => {
+      warn!(error = %e, "Could not start LAN discovery");
+      return;
+    },
+  };
+
+  info!(group = %GROUP, port = PORT, "Discovering peers on the LAN");
+
+  tokio::join!(
+    announce(node.clone(), socket.clone()),
+    listen(node.clone(), socket.clone()),
+  );
+}
+
+/**
+ * Bind a socket to the discovery port and join the group. Several nodes on
+ * one machine share the port.
+ */
+fn bind() -> io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+  socket.set_reuse_address(true)?;
+  #[cfg(unix)]
+  socket.set_reuse_port(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+  socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+  socket.set_multicast_loop_v4(true)?;
+
+  UdpSocket::from_std(socket.into())
+}
+
+async fn announce(node: Arc<Node>, socket: Arc<UdpSocket>) {
+  let group = SocketAddrV4::new(GROUP, PORT);
+
+  loop {
+    let announcement = serde_json::to_vec(&announcement(&node));
+
+    if let Ok(announcement) = announcement {
+      if let Err(e) = socket.send_to(&announcement, group).await {
+        warn!(error = %e, "Could not announce on the LAN");
+      }
+    }
+
+    sleep(ANNOUNCE_INTERVAL).await;
+  }
+}
+
+async fn listen(node: Arc<Node>, socket: Arc<UdpSocket>) {
+  let mut buffer = [0; 1024];
+
+  loop {
+    let (len, from) = match socket.recv_from(&mut buffer).await {
+      Ok(received) => received,
+      Err(e)       => {
+        warn!(error = %e, "Could not receive LAN announcements");
+        sleep(ANNOUNCE_INTERVAL).await;
+        continue;
+      },
+    };
+
+    let Ok(heard) = serde_json::from_slice::<Announcement>(&buffer[..len]) else {
+      continue;
+    };
+
+    let Some(addr) = dial_target(&node, &heard, from) else {
+      continue;
+    };
+
+    if node.has_peer(&heard.node_id).await {
+      continue;
+    }
+
+    node.addrs.lock().await.add(&addr);
+
+    match node.connect_to_peer(&addr).await {
+      Ok(peer_id) => info!(%addr, %peer_id, "Connected to a peer found on the LAN"),
+      Err(e)      => debug!(%addr, error = %e, "Could not connect to a peer found on the LAN"),
+    }
+  }
+}
+
+/**
+ * The announcement of this node.
+ */
+fn announcement(node: &Node) -> Announcement {
+  let port = node.listener
+    .local_addr()
+    .map_or(0, |addr| addr.port());
+
+  Announcement {
+    node_id: node.node_id.clone(),
+    network: node.network(),
+    genesis: node.genesis_hash(),
+    port,
+  }
+}
+
+/**
+ * The address to reach an announced node on, unless it is this node or on
+ * another network.
+ */
+fn dial_target(node: &Node, heard: &Announcement, from: SocketAddr) -> Option<String> {
+  if heard.node_id == node.node_id || heard.genesis != node.genesis_hash() {
+    return None;
+  }
+
+  Some(SocketAddr::new(from.ip(), heard.port).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_other_nodes_on_the_network_are_dialed() {
+      let node = Node::temp().await;
+      let from: SocketAddr = "192.168.1.20:45999".parse().unwrap();
+
+      let mut heard = announcement(&node);
+      assert_eq!(heard.network, "devnet");
+      assert_eq!(dial_target(&node, &heard, from), None);
+
+      heard.node_id = "someone else".to_string();
+      heard.port = 5002;
+      assert_eq!(dial_target(&node, &heard, from), Some("192.168.1.20:5002".to_string()));
+
+      heard.genesis = "ab".repeat(32);
+      assert_eq!(dial_target(&node, &heard, from), None);
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use bitflags::bitflags;
 use crate::blockchain::block::Block;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,6 +20,11 @@ pub enum MessageData {
   },
   BlockRequest { index: usize },
   BlockResponse { block: Block },
+  // Keepalive
+  Ping { nonce: u64 },
+  Pong { nonce: u64 },
+  // Sent by a node that is shutting down, before it closes the connection.
+  Goodbye {},
   // Misc
   Chat {
     message: String,
@@ -32,9 +38,93 @@ pub struct Message
     pub payload: MessageData,
 }
 
+/**
+ * The oldest and newest protocol versions this node speaks.
+ */
+pub const PROTOCOL_MIN: u32 = 1;
+pub const PROTOCOL_MAX: u32 = 1;
+
+/**
+ * The software the node runs, as sent in the handshake.
+ */
+pub const SOFTWARE: &str = concat!("cryptogram/", env!("CARGO_PKG_VERSION"));
+
+bitflags! {
+  /**
+   * What a node offers to its peers.
+   */
+  #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+  pub struct Services: u32 {
+    // Keeps the whole chain and serves blocks from it.
+    const FULL_NODE     = 1;
+    // Relays blocks and transactions to other peers.
+    const RELAY         = 1 << 1;
+    // Serves post media.
+    const MEDIA         = 1 << 2;
+    // Serves headers and proofs to light clients.
+    const LIGHT_SERVING = 1 << 3;
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Handshake
 {
-    pub version: String,
-    pub peer_id: String,
+    pub peer_id:     String,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub software:    String,
+    pub services:    Services,
+    pub tip_height:  u64,
+    pub tip_hash:    String,
+    // The address the node accepts connections on.
+    pub listen_addr: String,
+    pub genesis:     String,
+}
+
+impl Handshake {
+  /**
+   * Pick the highest protocol version both sides speak.
+   */
+  pub fn negotiate(&self, other: &Handshake) -> Option<u32> {
+    let min = self.min_version.max(other.min_version);
+    let max = self.max_version.min(other.max_version);
+
+    (min <= max).then_some(max)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(min_version: u32, max_version: u32) -> Handshake {
+      Handshake {
+        peer_id:     "peer".to_string(),
+        min_version,
+        max_version,
+        software:    SOFTWARE.to_string(),
+        services:    Services::FULL_NODE | Services::RELAY,
+        tip_height:  0,
+        tip_hash:    "0".to_string(),
+        listen_addr: "127.0.0.1:5000".to_string(),
+        genesis:     "0".to_string(),
+      }
+    }
+
+    #[test]
+    fn test_negotiate_picks_highest_common_version() {
+      assert_eq!(handshake(1, 3).negotiate(&handshake(2, 5)), Some(3));
+      assert_eq!(handshake(2, 5).negotiate(&handshake(1, 3)), Some(3));
+      assert_eq!(handshake(1, 1).negotiate(&handshake(1, 1)), Some(1));
+      assert_eq!(handshake(1, 2).negotiate(&handshake(3, 4)), None);
+    }
+
+    #[test]
+    fn test_services_round_trip() {
+      let sent = handshake(1, 1);
+      let json = serde_json::to_string(&sent).unwrap();
+      let received: Handshake = serde_json::from_str(&json).unwrap();
+
+      assert_eq!(received.services, Services::FULL_NODE | Services::RELAY);
+    }
 }
@@ -1,6 +1,11 @@
+pub mod addrbook;
+pub mod bans;
 pub mod node;
 pub mod peer;
 pub mod message;
+pub mod queue;
 pub mod gossip;
 pub mod input;
+pub mod lan;
+#[allow(clippy::module_inception)]
 pub mod p2p;
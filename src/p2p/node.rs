@@ -1,37 +1,83 @@
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, Semaphore};
 use serde_json;
 use std::error::Error;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::time::{interval_at, timeout, Duration, Instant};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use rand::seq::IteratorRandom;
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use tracing::{debug, info, info_span, warn, Instrument};
 use uuid::Uuid;
 use crate::p2p::message::Message;
 use crate::p2p::message::MessageData;
-use crate::p2p::message::Handshake;
-use crate::blockchain::chain::Blockchain;
+use crate::p2p::message::{Handshake, Services, PROTOCOL_MAX, PROTOCOL_MIN, SOFTWARE};
+use crate::blockchain::handle::ChainHandle;
+use crate::blockchain::miner::Miner;
+use crate::blockchain::block::{Block, BlockData};
+use crate::metrics::metrics;
+use crate::p2p::addrbook::AddressBook;
+use crate::p2p::bans::{BanList, Misbehavior, BAN_DURATION, BAN_THRESHOLD};
+use crate::p2p::p2p::P2pConfig;
+use crate::p2p::peer::{eviction_candidate, Direction, Peer, PeerInfo};
+use crate::p2p::queue::{queue, Push, RecvQueue, Traffic};
 
-type Peer = UnboundedSender<Message>;
+// How long a peer has to accept a connection, and to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often peers are pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long a peer can go without sending anything before it is dropped. Live
+// peers answer pings, so they are never quiet for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// The most connections accepted at once that haven't finished the handshake.
+const PENDING_HANDSHAKES: usize = 16;
+
+// The longest line a peer can send, which bounds the memory it can take up
+// before a message is parsed.
+const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+// The most addresses sent in, or taken from, one gossip message.
+pub const GOSSIP_ADDRESSES: usize = 32;
 
 #[derive(Debug, Clone)]
 pub struct Node {
   pub node_id:  String,
   pub peers:    Arc<Mutex<HashMap<String, Peer>>>,
-  pub chain:    Arc<Mutex<Blockchain>>,
+  pub chain:    ChainHandle,
+  pub miner:    Arc<Miner>,
   pub listener: Arc<TcpListener>,
+  pub addrs:    Arc<Mutex<AddressBook>>,
+  pub bans:     Arc<Mutex<BanList>>,
+  // The number of outbound connections to keep up, and the most allowed.
+  pub outbound:    usize,
+  pub max_inbound: usize,
+  // The most connections from one address.
+  pub max_per_ip:  usize,
+  // The number of connections made, for numbering them.
+  connections:     Arc<AtomicU64>,
+  // Limits the connections accepted but not yet through the handshake.
+  pub handshakes:  Arc<Semaphore>,
+  // Addresses this node was found to be reachable on.
+  self_ips:        Arc<Mutex<HashSet<IpAddr>>>,
+  // Set when the node starts shutting down.
+  stopping:        Arc<watch::Sender<bool>>,
 }
 
 impl Node {
-  pub async fn new(chain: Arc<Mutex<Blockchain>>, addr: String) -> Self {
+  pub async fn new(chain: ChainHandle, miner: Arc<Miner>, config: &P2pConfig) -> Self {
     let node_id = Uuid::new_v4().to_string();
 
-    println!("Running P2P on {}, Node ID: {}", addr, node_id);
+    info!(addr = %config.addr, node_id = %node_id, "Running P2P");
 
-    let listener = TcpListener::bind(&addr)
+    let listener = TcpListener::bind(&config.addr)
       .await
       .unwrap();
 
@@ -39,10 +85,45 @@ impl Node {
       node_id,
       peers:    Arc::new(Mutex::new(HashMap::new())),
       listener: Arc::new(listener),
+      addrs:    Arc::new(Mutex::new(AddressBook::open(config.address_book.clone()))),
+      bans:     Arc::new(Mutex::new(BanList::open(config.bans.clone()))),
+      outbound:    config.outbound,
+      max_inbound: config.max_inbound,
+      max_per_ip:  config.max_per_ip,
+      connections: Arc::new(AtomicU64::new(0)),
+      handshakes:  Arc::new(Semaphore::new(PENDING_HANDSHAKES)),
+      self_ips:    Arc::new(Mutex::new(HashSet::new())),
+      stopping:    Arc::new(watch::Sender::new(false)),
       chain,
+      miner,
     }
   }
 
+  /**
+   * Start a node on a free local port, keeping its files in a temporary
+   * directory.
+   */
+  #[cfg(test)]
+  pub async fn temp() -> Self {
+    let dir = crate::blockchain::temp_dir();
+
+    let config = P2pConfig {
+      addr:         "127.0.0.1:0".to_string(),
+      peers:        vec![],
+      address_book: dir.join("peers.json"),
+      bans:         dir.join("bans.json"),
+      outbound:     8,
+      max_inbound:  32,
+      max_per_ip:   4,
+      lan:          false,
+      console:      false,
+    };
+
+    let chain = crate::blockchain::chain::Blockchain::temp();
+
+    Self::new(ChainHandle::start(chain), Arc::new(Miner::new(1)), &config).await
+  }
+
   pub fn get_local_addr(&self) -> String {
     self.listener
       .local_addr()
@@ -54,161 +135,537 @@ impl Node {
    * Connect to a peer using their address.
    */
   pub async fn connect_to_peer(&self, peer: &str) -> Result<String, Box<dyn Error>> {
-    let stream = TcpStream::connect(peer).await?;
+    self.connect(peer, true).await
+  }
+
+  /**
+   * Connect to a configured peer. Those are always kept, so they don't wait
+   * for an outbound slot to free up.
+   */
+  pub async fn connect_to_persistent_peer(&self, peer: &str) -> Result<String, Box<dyn Error>> {
+    self.connect(peer, false).await
+  }
+
+  async fn connect(&self, peer: &str, capped: bool) -> Result<String, Box<dyn Error>> {
+    // The error isn't Send, so it can't be held while waiting on the book.
+    let connected = self.dial(peer, capped)
+      .await
+      .map_err(|e| e.to_string());
+
+    let mut addrs = self.addrs.lock().await;
+    match connected {
+      Ok(_)  => addrs.record_success(peer),
+      Err(_) => addrs.record_failure(peer),
+    }
+
+    Ok(connected?)
+  }
+
+  /**
+   * Connect to a peer and ask it for its peers and the blocks this node is
+   * missing.
+   */
+  pub async fn join(&self, peer: &str) -> Result<String, Box<dyn Error>> {
+    let peer_id = self.connect_to_peer(peer).await?;
 
-    let (
-      mut reader,
-      mut writer,
-    ) = stream.into_split();
+    let height = self.chain
+      .reader()
+      .height() as usize;
 
-    self.send_handshake(&mut writer).await?;
+    self.send(&peer_id, &MessageData::PeerDiscovery {}).await;
+    self.request_block(&peer_id, height + 1).await;
 
-    let handshake = self.recv_handshake(&mut reader).await?;
+    Ok(peer_id)
+  }
+
+  async fn dial(&self, peer: &str, capped: bool) -> Result<String, Box<dyn Error>> {
+    if capped && self.count_outbound().await >= self.outbound {
+      return Err("No outbound slots left".into());
+    }
+
+    let stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(peer))
+      .await
+      .map_err(|_| "Connection timed out")??;
+
+    let remote = stream.peer_addr()?;
+
+    if self.is_self_addr(&remote).await {
+      self.addrs.lock().await.remove(&remote.to_string());
+      return Err("Cannot connect to self".into());
+    }
+
+    if self.is_banned(&remote.ip()).await {
+      return Err("Peer is banned".into());
+    }
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let (handshake, version) = timeout(HANDSHAKE_TIMEOUT, async {
+      self.send_handshake(&mut writer).await?;
+      self.recv_handshake(&mut reader).await
+    })
+      .await
+      .map_err(|_| "Handshake timed out")??;
+
+    if handshake.peer_id == self.node_id {
+      self.addrs.lock().await.remove(&remote.to_string());
+      return Err("Cannot connect to self".into());
+    }
+
+    let peer_id = handshake.peer_id.clone();
 
     self.setup_peer(
-      handshake.peer_id.clone(),
+      handshake,
+      version,
+      remote,
+      Direction::Outbound,
       reader,
       writer,
-    ).await;
+    ).await?;
 
-    Ok(handshake.peer_id.clone())
+    Ok(peer_id)
   }
 
   /**
    * Handle an incoming peer connection.
    */
   pub async fn handle_incoming(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let (
-      mut reader,
-      mut writer,
-    ) = stream.into_split();
+    let remote = stream.peer_addr()?;
+    let local = stream.local_addr()?;
 
-    let handshake = self.recv_handshake(&mut reader).await?;
+    if self.is_banned(&remote.ip()).await {
+      return Err("Peer is banned".into());
+    }
 
-    self.send_handshake(&mut writer).await?;
+    if self.count_from(&remote.ip()).await >= self.max_per_ip {
+      return Err(format!("Too many connections from {}", remote.ip()).into());
+    }
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let (handshake, version) = timeout(HANDSHAKE_TIMEOUT, async {
+      let received = self.recv_handshake(&mut reader).await?;
+      self.send_handshake(&mut writer).await?;
+      Ok::<_, Box<dyn Error>>(received)
+    })
+      .await
+      .map_err(|_| "Handshake timed out")??;
+
+    if handshake.peer_id == self.node_id {
+      // The node dialed itself, so the address it came in on is its own.
+      self.self_ips.lock().await.insert(local.ip());
+      return Err("Cannot connect to self".into());
+    }
+
+    if !self.make_room().await {
+      return Err("No inbound slots left".into());
+    }
 
     self.setup_peer(
-      handshake.peer_id,
+      handshake,
+      version,
+      remote,
+      Direction::Inbound,
       reader,
       writer,
-    ).await;
+    ).await?;
 
     Ok(())
   }
 
   /**
-   * Configure the communication channel for a peer.
+   * Configure the communication channel for a peer. The connection is read
+   * and written by two tasks, and when either one stops the other is stopped
+   * and the peer is dropped.
    */
-  async fn setup_peer(&self, peer_id: String, reader: OwnedReadHalf, mut writer: OwnedWriteHalf) {
-    let (tx, mut rx): (
-      UnboundedSender<Message>,
-      UnboundedReceiver<Message>,
-    ) = unbounded_channel();
+  async fn setup_peer(
+    &self,
+    handshake: Handshake,
+    version: u32,
+    remote: SocketAddr,
+    direction: Direction,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+  ) -> Result<(), Box<dyn Error>> {
+    // A connection finishing its handshake while the node shuts down would
+    // be left open.
+    if self.is_stopping() {
+      return Err("The node is shutting down".into());
+    }
 
-    self.peers
-      .lock()
-      .await
-      .insert(peer_id.clone(), tx.clone());
+    let (queue, rx) = queue();
+    let conn = self.connections.fetch_add(1, Ordering::Relaxed);
 
-    let peer_clone = peer_id.clone();
-    let node_clone = self.clone();
+    let peer = Peer::new(handshake, version, remote, direction, queue, conn);
+    let peer_id = peer.peer_id().to_string();
+    let traffic = peer.traffic.clone();
 
-    tokio::spawn(async move {
-      while let Some(msg) = rx.recv().await {
-        if let Ok(data) = serde_json::to_string(&msg) {
+    let addr = peer.addr.clone().filter(|_| !peer.is_outbound());
 
-          writer.write_all(data.as_bytes()).await.unwrap();
-          writer.write_all(b"\n").await.unwrap();
+    // Everything logged about the connection carries the peer.
+    let span = info_span!(parent: None, "peer", peer_id = %peer_id, conn, remote = %remote);
 
-          if writer.flush().await.is_err() {
-            println!("Disconnected from peer");
+    span.in_scope(|| info!(
+      software = %peer.handshake.software,
+      version,
+      tip_height = peer.tip_height,
+      direction = ?direction,
+      "Connected",
+    ));
 
-            node_clone.rem_peer(&peer_clone).await;
+    {
+      let mut peers = self.peers.lock().await;
 
-            break;
-          }
-        }
+      // The id is only claimed by the peer, so a second connection with it
+      // would take the place of the first.
+      if peers.contains_key(&peer_id) {
+        span.in_scope(|| warn!("Already connected to a peer with the same id"));
+        return Err("Already connected to a peer with the same id".into());
       }
-    });
 
-    let self_clone = self.clone();
+      peers.insert(peer_id.clone(), peer);
+    }
 
-    tokio::spawn(async move {
-      let mut reader = BufReader::new(reader);
-      let mut buffer = String::new();
+    if let Some(addr) = addr {
+      self.addrs.lock().await.add(&addr);
+    }
 
-      while reader.read_line(&mut buffer).await.unwrap() > 0 {
-        if let Ok(message) = serde_json::from_str::<Message>(&buffer.trim()) {
-          self_clone.handle_message(message).await;
-        }
-        buffer.clear();
+    let reading = tokio::spawn({
+      let node = self.clone();
+      let peer_id = peer_id.clone();
+      let traffic = traffic.clone();
+
+      async move {
+        node.read_peer(&peer_id, conn, reader, &traffic).await;
+        node.disconnect(&peer_id, conn).await;
+      }
+    }.instrument(span.clone()));
+
+    let writing = tokio::spawn({
+      let node = self.clone();
+      let peer_id = peer_id.clone();
+
+      async move {
+        node.write_peer(rx, writer, &traffic).await;
+        reading.abort();
+        node.disconnect(&peer_id, conn).await;
       }
-    });
+    }.instrument(span));
+
+    if let Some(peer) = self.peers.lock().await.get_mut(&peer_id).filter(|peer| peer.conn == conn) {
+      peer.writer = Some(writing);
+    }
+
+    Ok(())
   }
 
-  async fn handle_message(&self, message: Message) {
+  /**
+   * Read messages from a peer until it disconnects, goes quiet, or is
+   * dropped.
+   */
+  async fn read_peer(&self, peer_id: &str, conn: u64, mut reader: BufReader<OwnedReadHalf>, traffic: &Traffic) {
+    let mut buffer = String::new();
+
+    loop {
+      match timeout(IDLE_TIMEOUT, read_message(&mut reader, &mut buffer)).await {
+        Ok(Ok(Some(0))) => break,
+        Ok(Ok(Some(n))) => traffic.received(n),
+        Ok(Ok(None)) => {
+          traffic.received(buffer.len());
+          self.misbehaving(peer_id, Misbehavior::Oversized).await;
+          break;
+        },
+        Ok(Err(e)) => {
+          warn!(error = %e, "Could not read from the peer");
+          break;
+        },
+        Err(_) => {
+          info!("Peer timed out");
+          break;
+        },
+      }
+
+      match parse_message(&buffer) {
+        Ok(Some(message)) => self.handle_message(peer_id, message).await,
+        Ok(None) => debug!("Ignoring a message this node doesn't know"),
+        Err(misbehavior) => self.misbehaving(peer_id, misbehavior).await,
+      }
+      buffer.clear();
+
+      // The peer was banned.
+      if !self.is_connected(peer_id, conn).await {
+        break;
+      }
+    }
+  }
+
+  /**
+   * Write the messages queued for a peer, and ping it when the queue is quiet,
+   * until the peer is dropped or a write fails.
+   */
+  async fn write_peer(&self, mut rx: RecvQueue, mut writer: OwnedWriteHalf, traffic: &Traffic) {
+    let mut ping = interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+    loop {
+      let message = tokio::select! {
+        message = rx.recv() => match message {
+          Some(message) => message,
+          None          => break,
+        },
+        _ = ping.tick() => Message {
+          payload: MessageData::Ping { nonce: rand::random() },
+          sender:  self.node_id.clone(),
+        },
+      };
+
+      let Ok(mut data) = serde_json::to_string(&message) else {
+        continue;
+      };
+      data.push('\n');
+
+      // A peer that doesn't take its messages is as good as gone.
+      match timeout(IDLE_TIMEOUT, writer.write_all(data.as_bytes())).await {
+        Ok(Ok(())) => traffic.sent(data.len()),
+        Ok(Err(e)) => {
+          warn!(error = %e, "Could not write to the peer");
+          break;
+        },
+        Err(_) => {
+          info!("Peer stopped reading");
+          break;
+        },
+      }
+    }
+
+    let _ = writer.shutdown().await;
+  }
+
+  /**
+   * Handle a message from a peer. Replies go to the connection the message
+   * came in on, not to the sender it claims.
+   */
+  async fn handle_message(&self, peer_id: &str, message: Message) {
     match message.payload {
       MessageData::Chat { message: msg } => {
-        println!("[{}] {}", message.sender, msg);
+        info!(message = %msg, "Chat");
+      },
+      MessageData::Ping { nonce } => {
+        self.send(peer_id, &MessageData::Pong { nonce }).await;
+      },
+      // Any message shows the peer is alive, so a pong needs no handling.
+      MessageData::Pong { .. } => {},
+      MessageData::Goodbye {} => {
+        info!("Peer is shutting down");
+        self.rem_peer(peer_id).await;
       },
       MessageData::PeerDiscovery {} => {
-        self.send(&message.sender, &MessageData::PeerGossip {
-          peers: self.get_peers().await,
+        self.send(peer_id, &MessageData::PeerGossip {
+          peers: self.addrs.lock().await.sample(GOSSIP_ADDRESSES),
         }).await;
       },
-      // MessageData::PeerGossip { peers } => {
-      //   for peer in peers {
-      //     node.add_peer(&peer).await;
-      //   }
-      // },
+      MessageData::PeerGossip { peers } => {
+        let sender = self.peers
+          .lock()
+          .await
+          .get_mut(peer_id)
+          .map(|peer| (peer.ip(), peer.gossip_budget()));
+
+        let Some((ip, budget)) = sender else {
+          return;
+        };
+
+        let added = self.addrs
+          .lock()
+          .await
+          .add_gossiped(&peers[..peers.len().min(GOSSIP_ADDRESSES)], ip, budget);
+
+        if let Some(peer) = self.peers.lock().await.get_mut(peer_id) {
+          peer.spend_gossip(added);
+        }
+      },
       MessageData::BlockchainTx { block } => {
-        println!("BlockchainTx: {:?}", block);
+        debug!(hash = %block.hash, index = block.index, "Received a block");
+
+        if !self.check_block(peer_id, &block).await {
+          return;
+        }
+
+        self.peer_has_block(peer_id, block.index).await;
+
+        let hash = block.hash.clone();
 
         self.chain
-          .lock()
+          .write(move |chain| chain.add_block(block))
           .await
-          .add_block(block)
-          .unwrap_or_else(|e| println!("{}", e));
+          .unwrap_or_else(|e| warn!(%hash, error = %e, "Could not add the block"));
       },
       // When another node asks for a block, reply with the block at the index
       // which the node asked for.
       MessageData::BlockRequest { index } => {
-        println!("BlockRequest: {:?}", index);
+        debug!(index, "Block requested");
 
         let block = self.chain
-          .lock()
-          .await
+          .reader()
           .at(index);
 
         if let Some(block) = block {
-          self.send(&message.sender, &MessageData::BlockResponse { block }).await;
+          self.send(peer_id, &MessageData::BlockResponse { block }).await;
         }
       },
       // When receiving a block, add it to the chain and ask a random peer for
       // the next block. This will loop back until the chain is synced.
       MessageData::BlockResponse { block } => {
-        println!("BlockRequest: {:?}", block);
+        debug!(hash = %block.hash, index = block.index, "Received a requested block");
 
-        self.chain
-          .lock()
-          .await
-          .add_block(block.clone())
-          .unwrap_or_else(|e| println!("{}", e));
+        if !self.take_request(peer_id, block.index as usize).await {
+          self.misbehaving(peer_id, Misbehavior::Unsolicited).await;
+          return;
+        }
 
-        let peer = self.get_random_peer()
-          .await
-          .unwrap();
+        if !self.check_block(peer_id, &block).await {
+          return;
+        }
 
-        self.send(&peer, &MessageData::BlockRequest {
-          index: (block.index as usize) + 1,
-        }).await;
+        self.peer_has_block(peer_id, block.index).await;
+
+        let hash = block.hash.clone();
+        let index = block.index as usize;
+
+        if let Err(e) = self.chain.write(move |chain| chain.add_block(block)).await {
+          // The next block wouldn't go on top of the chain either.
+          warn!(%hash, error = %e, "Could not add the block");
+          return;
+        }
+
+        // The peer set can empty out while the block is added.
+        if let Some(peer) = self.get_random_peer().await {
+          self.request_block(&peer, index + 1).await;
+        }
       },
       _ => {
-        eprintln!("Unknown message.");
+        debug!("Unknown message");
       },
     }
   }
 
+  /**
+   * Check the parts of a block that don't depend on the rest of the chain, and
+   * that it comes after the top block. A peer sending a block that fails them
+   * is misbehaving, where one that fails against the chain may only be late.
+   */
+  async fn check_block(&self, peer_id: &str, block: &Block) -> bool {
+    let height = self.chain.reader().height();
+
+    let (misbehavior, reason) = if block.validate_signature().is_err() {
+      (Misbehavior::InvalidSignature, "signature")
+    } else if block.validate_work().is_err() {
+      (Misbehavior::InvalidWork, "work")
+    } else if block.index == 0 || block.index <= height {
+      // Peers racing to relay the same block land here too, so it only
+      // scores a little.
+      (Misbehavior::Stale, "stale")
+    } else {
+      return true;
+    };
+
+    metrics().block_rejected(reason);
+    self.misbehaving(peer_id, misbehavior).await;
+    false
+  }
+
+  /**
+   * Count misbehavior against a peer, and ban it once its score reaches the
+   * threshold.
+   */
+  pub async fn misbehaving(&self, peer_id: &str, misbehavior: Misbehavior) {
+    let ip = {
+      let mut peers = self.peers.lock().await;
+
+      let Some(peer) = peers.get_mut(peer_id) else {
+        return;
+      };
+
+      peer.score += misbehavior.score();
+      warn!(peer_id, %misbehavior, score = peer.score, "Peer misbehaved");
+
+      if peer.score < BAN_THRESHOLD {
+        return;
+      }
+
+      peer.ip()
+    };
+
+    self.ban(ip, BAN_DURATION, &misbehavior.to_string()).await;
+  }
+
+  /**
+   * Ban an address and disconnect the peers connected from it.
+   */
+  pub async fn ban(&self, ip: IpAddr, seconds: u64, reason: &str) {
+    {
+      let mut bans = self.bans.lock().await;
+
+      bans.ban(ip, seconds, reason);
+      if let Err(e) = bans.save() {
+        warn!(error = %e, "Could not save the ban list");
+      }
+    }
+
+    // Dropping the peer closes its channel, which ends the writer task.
+    self.peers
+      .lock()
+      .await
+      .retain(|_, peer| peer.ip() != ip);
+
+    warn!(%ip, reason, "Banned");
+  }
+
+  /**
+   * Lift the ban on an address. Returns false when it wasn't banned.
+   */
+  pub async fn unban(&self, ip: &IpAddr) -> bool {
+    let mut bans = self.bans.lock().await;
+
+    let unbanned = bans.unban(ip);
+    if let Err(e) = bans.save() {
+      warn!(error = %e, "Could not save the ban list");
+    }
+
+    unbanned
+  }
+
+  /**
+   * Check if an address is banned.
+   */
+  pub async fn is_banned(&self, ip: &IpAddr) -> bool {
+    self.bans.lock().await.is_banned(ip)
+  }
+
+  /**
+   * Ask a peer for the block at an index.
+   */
+  pub async fn request_block(&self, peer_id: &str, index: usize) {
+    if let Some(peer) = self.peers.lock().await.get_mut(peer_id) {
+      peer.requested.insert(index);
+    }
+
+    self.send(peer_id, &MessageData::BlockRequest { index }).await;
+  }
+
+  /**
+   * Mark a requested block as received. Returns false when it wasn't asked
+   * for.
+   */
+  async fn take_request(&self, peer_id: &str, index: usize) -> bool {
+    self.peers
+      .lock()
+      .await
+      .get_mut(peer_id)
+      .is_some_and(|peer| peer.requested.remove(&index))
+  }
+
   /**
    * Check if a peer exists.
    */
@@ -223,6 +680,127 @@ impl Node {
     self.peers.lock().await.remove(peer);
   }
 
+  /**
+   * Check if a connection is still the one to the peer. A peer that
+   * reconnects replaces its earlier connection.
+   */
+  async fn is_connected(&self, peer_id: &str, conn: u64) -> bool {
+    self.peers
+      .lock()
+      .await
+      .get(peer_id)
+      .is_some_and(|peer| peer.conn == conn)
+  }
+
+  /**
+   * Drop a peer when its connection ends, unless it has reconnected since.
+   */
+  async fn disconnect(&self, peer_id: &str, conn: u64) {
+    let mut peers = self.peers.lock().await;
+
+    if peers.get(peer_id).is_some_and(|peer| peer.conn == conn) {
+      peers.remove(peer_id);
+      info!("Disconnected");
+    }
+  }
+
+  /**
+   * Count the peers the node dialed.
+   */
+  async fn count_outbound(&self) -> usize {
+    self.peers.lock().await
+      .values()
+      .filter(|peer| peer.is_outbound())
+      .count()
+  }
+
+  /**
+   * Count the peers connected from an address.
+   */
+  async fn count_from(&self, ip: &IpAddr) -> usize {
+    self.peers.lock().await
+      .values()
+      .filter(|peer| peer.ip() == *ip)
+      .count()
+  }
+
+  /**
+   * Make room for an inbound peer, evicting one when the inbound slots are
+   * full. Returns false when none of the peers can be evicted.
+   */
+  async fn make_room(&self) -> bool {
+    let mut peers = self.peers.lock().await;
+
+    let inbound: Vec<&Peer> = peers
+      .values()
+      .filter(|peer| !peer.is_outbound())
+      .collect();
+
+    if inbound.len() < self.max_inbound {
+      return true;
+    }
+
+    let Some(evicted) = eviction_candidate(&inbound) else {
+      return false;
+    };
+
+    peers.remove(&evicted);
+    info!(peer_id = %evicted, "Evicted a peer to make room for a new one");
+
+    true
+  }
+
+  /**
+   * Check if an address is this node's own.
+   */
+  async fn is_self_addr(&self, addr: &SocketAddr) -> bool {
+    let port = self.listener
+      .local_addr()
+      .map(|local| local.port())
+      .ok();
+
+    if port != Some(addr.port()) {
+      return false;
+    }
+
+    addr.ip().is_loopback()
+      || addr.ip().is_unspecified()
+      || self.self_ips.lock().await.contains(&addr.ip())
+  }
+
+  /**
+   * Retrieve the addresses of the peers the node dialed.
+   */
+  pub async fn get_outbound_addrs(&self) -> Vec<String> {
+    self.peers.lock().await
+      .values()
+      .filter(|peer| peer.is_outbound())
+      .filter_map(|peer| peer.addr.clone())
+      .collect()
+  }
+
+  /**
+   * Describe the connected peers, longest connected first.
+   */
+  pub async fn get_peer_infos(&self) -> Vec<PeerInfo> {
+    let mut peers: Vec<PeerInfo> = self.peers.lock().await
+      .values()
+      .map(Peer::info)
+      .collect();
+
+    peers.sort_by(|a, b| a.connected_at.cmp(&b.connected_at).then(a.peer_id.cmp(&b.peer_id)));
+    peers
+  }
+
+  /**
+   * Note that a peer has a block.
+   */
+  async fn peer_has_block(&self, peer_id: &str, index: u64) {
+    if let Some(peer) = self.peers.lock().await.get_mut(peer_id) {
+      peer.has_block(index);
+    }
+  }
+
   /**
    * Retrieve the node peers.
    */
@@ -246,20 +824,29 @@ impl Node {
   }
 
   /**
-   * Send message to a peer.
+   * Send message to a peer. The message is dropped when the queue to the peer
+   * is full, and the peer with it when the queue stays full.
    */
-  pub async fn send(&self, peer: &str, payload: &MessageData) {
+  pub async fn send(&self, peer_id: &str, payload: &MessageData) {
     let message = Message {
       payload: payload.to_owned(),
       sender: self.node_id.clone(),
     };
 
-    let peers = self.peers.lock().await;
+    let mut peers = self.peers.lock().await;
 
-    if let Some(sender) = peers.get(peer) {
-      let _ = sender.send(message);
-    } else {
-      println!("No such peer: {}", peer);
+    let Some(peer) = peers.get_mut(peer_id) else {
+      debug!(peer_id, "No such peer");
+      return;
+    };
+
+    match peer.queue.push(message) {
+      Push::Queued | Push::Closed => {},
+      Push::Dropped => peer.traffic.dropped(),
+      Push::Stalled => {
+        peers.remove(peer_id);
+        warn!(peer_id, "Peer stopped taking messages, disconnecting");
+      },
     }
   }
 
@@ -272,30 +859,110 @@ impl Node {
     }
   }
 
+  /**
+   * Start shutting the node down. New connections are refused and the
+   * miner gives up on its block.
+   */
+  pub fn stop(&self) {
+    self.miner.stop();
+    self.stopping.send_replace(true);
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    *self.stopping.borrow()
+  }
+
+  /**
+   * Wait until the node starts shutting down.
+   */
+  pub async fn stopped(&self) {
+    let _ = self.stopping
+      .subscribe()
+      .wait_for(|stopping| *stopping)
+      .await;
+  }
+
+  /**
+   * Say goodbye to every peer and close the connections once the messages
+   * queued for them are written, then save the address book.
+   */
+  pub async fn close(&self) {
+    self.yell(&MessageData::Goodbye {}).await;
+
+    // Dropping a peer drops its queue, which its writer then writes out.
+    let writers: Vec<_> = self.peers
+      .lock()
+      .await
+      .drain()
+      .filter_map(|(_, mut peer)| peer.writer.take())
+      .collect();
+
+    for writer in writers {
+      let _ = writer.await;
+    }
+
+    if let Err(e) = self.addrs.lock().await.save() {
+      warn!(error = %e, "Could not save the address book");
+    }
+  }
+
   /**
    * Sync the node with a random peer.
    */
   pub async fn sync(&self) {
-    match self.get_random_peer().await {
-      Some(peer) => {
-        self.send(&peer, &MessageData::BlockRequest {
-          index: self.chain
-            .lock()
-            .await
-            .len(),
-        }).await;
+    if let Some(peer) = self.get_random_peer().await {
+      let height = self.chain
+        .reader()
+        .height();
 
-        println!("Requesting blockchain sync");
-      }
-      None => {}
+      // The node has the block at its height, so it asks for the one after.
+      self.request_block(&peer, height as usize + 1).await;
+
+      info!(peer_id = %peer, height, "Requesting blockchain sync");
+    }
+  }
+
+  pub fn genesis_hash(&self) -> String {
+    self.chain
+      .reader()
+      .at(0)
+      .map(|block| block.hash)
+      .unwrap_or_default()
+  }
+
+  /**
+   * The name of the network, from the genesis block.
+   */
+  pub fn network(&self) -> String {
+    match self.chain.reader().at(0).map(|block| block.data) {
+      Some(BlockData::Genesis { network, .. }) => network,
+      _                                        => String::new(),
+    }
+  }
+
+  /**
+   * The handshake this node introduces itself with.
+   */
+  fn handshake(&self) -> Handshake {
+    let tip = self.chain
+      .reader()
+      .top_block();
+
+    Handshake {
+      peer_id:     self.node_id.clone(),
+      min_version: PROTOCOL_MIN,
+      max_version: PROTOCOL_MAX,
+      software:    SOFTWARE.to_string(),
+      services:    Services::FULL_NODE | Services::RELAY,
+      tip_height:  tip.index,
+      tip_hash:    tip.hash,
+      listen_addr: self.get_local_addr(),
+      genesis:     self.genesis_hash(),
     }
   }
 
   async fn send_handshake(&self, writer: &mut OwnedWriteHalf) -> Result<(), Box<dyn Error>> {
-    let sending = Handshake {
-      version: "1".to_string(),
-      peer_id: self.node_id.clone(),
-    };
+    let sending = self.handshake();
 
     // Send handshake
     writer.write_all(serde_json::to_string(&sending)?.as_bytes()).await?;
@@ -304,24 +971,447 @@ impl Node {
     Ok(())
   }
 
-  async fn recv_handshake(&self, reader: &mut OwnedReadHalf) -> Result<Handshake, Box<dyn Error>> {
-    let mut reader = BufReader::new(reader);
+  /**
+   * Receive the handshake of a peer, and the protocol version to talk to it
+   * with.
+   */
+  async fn recv_handshake(&self, reader: &mut BufReader<OwnedReadHalf>) -> Result<(Handshake, u32), Box<dyn Error>> {
     let mut buffer = String::new();
 
-    reader.read_line(&mut buffer).await.unwrap();
-    let handshake = serde_json::from_str::<Handshake>(&buffer.trim()).unwrap();
-    buffer.clear();
+    match read_message(reader, &mut buffer).await? {
+      Some(0) => return Err("Connection closed during the handshake".into()),
+      None    => return Err("Handshake is too long".into()),
+      Some(_) => {},
+    }
+
+    let handshake = serde_json::from_str::<Handshake>(buffer.trim())?;
 
     // Validate handshake.
-    if handshake.version != "1" {
-      return Err("Invalid handshake version".into());
-    }
+    let Some(version) = self.handshake().negotiate(&handshake) else {
+      return Err(format!(
+        "No common protocol version, peer speaks {} to {}",
+        handshake.min_version, handshake.max_version,
+      ).into());
+    };
 
-    if handshake.peer_id == self.node_id {
-      return Err("Cannot connect to self".into());
+    if handshake.genesis != self.genesis_hash() {
+      return Err("Peer is on a different network".into());
     }
 
-    Ok(handshake)
+    Ok((handshake, version))
   }
+}
+
+/**
+ * Read a line from a peer, up to `MAX_MESSAGE_BYTES`. Returns the number of
+ * bytes read, or `None` when the line goes on past the limit.
+ */
+async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R, buffer: &mut String) -> io::Result<Option<usize>> {
+  let n = reader
+    .take(MAX_MESSAGE_BYTES)
+    .read_line(buffer)
+    .await?;
+
+  if n as u64 == MAX_MESSAGE_BYTES && !buffer.ends_with('\n') {
+    return Ok(None);
+  }
+
+  Ok(Some(n))
+}
+
+/**
+ * Parse a line from a peer. JSON that isn't a message this node knows, like
+ * a message type from a newer version, is left for the node to ignore, so
+ * that only lines that aren't JSON at all count against the peer.
+ */
+fn parse_message(line: &str) -> Result<Option<Message>, Misbehavior> {
+  if let Ok(message) = serde_json::from_str::<Message>(line.trim()) {
+    return Ok(Some(message));
+  }
+
+  match serde_json::from_str::<serde_json::Value>(line.trim()) {
+    Ok(_)  => Ok(None),
+    Err(_) => Err(Misbehavior::Malformed),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockData;
+    use crate::blockchain::testing::Account;
+
+    /**
+     * Add a peer without a connection behind it.
+     */
+    async fn add_peer(node: &Node, peer_id: &str, ip: &str) -> RecvQueue {
+      let (queue, receiver) = queue();
+      let mut handshake = node.handshake();
+      handshake.peer_id = peer_id.to_string();
+
+      let remote = SocketAddr::new(ip.parse().unwrap(), 5000);
+      let peer = Peer::new(handshake, PROTOCOL_MAX, remote, Direction::Inbound, queue, 0);
+
+      node.peers.lock().await.insert(peer_id.to_string(), peer);
+
+      receiver
+    }
+
+    fn message(payload: MessageData) -> Message {
+      Message {
+        sender: "someone else".to_string(),
+        payload,
+      }
+    }
+
+    #[tokio::test]
+    async fn test_misbehaving_peer_is_banned() {
+      let node = Node::temp().await;
+      let _rx = add_peer(&node, "mallory", "10.0.0.1").await;
+
+      for _ in 0..9 {
+        node.misbehaving("mallory", Misbehavior::Malformed).await;
+      }
+      assert_eq!(node.peers.lock().await["mallory"].score, 90);
+      assert!(!node.is_banned(&"10.0.0.1".parse().unwrap()).await);
+
+      node.misbehaving("mallory", Misbehavior::Malformed).await;
+
+      assert!(!node.has_peer("mallory").await);
+      assert!(node.is_banned(&"10.0.0.1".parse().unwrap()).await);
+      assert_eq!(node.bans.lock().await.list()[0].reason, "malformed message");
+    }
 
+    #[tokio::test]
+    async fn test_unsolicited_block_response_is_scored() {
+      let node = Node::temp().await;
+      let _rx = add_peer(&node, "mallory", "10.0.0.1").await;
+      let block = node.chain.reader().at(0).unwrap();
+
+      node.handle_message("mallory", message(MessageData::BlockResponse { block })).await;
+
+      assert_eq!(node.peers.lock().await["mallory"].score, Misbehavior::Unsolicited.score());
+    }
+
+    #[tokio::test]
+    async fn test_genesis_from_a_peer_is_refused() {
+      let node = Node::temp().await;
+      let _rx = add_peer(&node, "mallory", "10.0.0.1").await;
+      let genesis = node.genesis_hash();
+
+      let post = BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      };
+
+      let mut block = Block::new(post.clone(), 0, "0".to_string());
+      block.public_key = Account::new(1).public_key();
+      block.signature  = Account::new(1).sign(&post);
+      block.mine_block(post.rules().difficulty);
+
+      node.handle_message("mallory", message(MessageData::BlockchainTx { block })).await;
+
+      assert_eq!(node.genesis_hash(), genesis);
+    }
+
+    #[tokio::test]
+    async fn test_forged_block_is_scored() {
+      let node = Node::temp().await;
+      let _rx = add_peer(&node, "mallory", "10.0.0.1").await;
+
+      let mut block = Block::next(&node.chain.reader().top_block(), BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      });
+      block.public_key = Account::new(1).public_key();
+      block.signature  = Account::new(1).sign(&block.data);
+
+      node.handle_message("mallory", message(MessageData::BlockchainTx { block })).await;
+
+      assert_eq!(node.peers.lock().await["mallory"].score, Misbehavior::InvalidWork.score());
+      assert_eq!(node.chain.reader().height(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_block_is_scored() {
+      let node = Node::temp().await;
+      let _rx = add_peer(&node, "mallory", "10.0.0.1").await;
+
+      let post = BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      };
+
+      let mut block = Block::new(post.clone(), 0, "0".to_string());
+      block.public_key = Account::new(1).public_key();
+      block.signature  = Account::new(1).sign(&post);
+      block.mine_block(post.rules().difficulty);
+
+      node.handle_message("mallory", message(MessageData::BlockchainTx { block })).await;
+
+      assert_eq!(node.peers.lock().await["mallory"].score, Misbehavior::Stale.score());
+      assert_eq!(node.chain.reader().height(), 0);
+    }
+
+    #[test]
+    fn test_unknown_messages_are_ignored() {
+      let ping = r#"{"sender":"alice","payload":{"type":"Ping","nonce":7}}"#;
+      let unknown = r#"{"sender":"alice","payload":{"type":"Teleport","to":"mars"}}"#;
+
+      assert!(matches!(parse_message(ping), Ok(Some(Message { payload: MessageData::Ping { nonce: 7 }, .. }))));
+      assert!(matches!(parse_message(unknown), Ok(None)));
+      assert!(matches!(parse_message("not json"), Err(Misbehavior::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn test_long_lines_are_cut_off() {
+      let mut buffer = String::new();
+      let mut reader = BufReader::new(&b"{}\n"[..]);
+      assert_eq!(read_message(&mut reader, &mut buffer).await.unwrap(), Some(3));
+
+      let line = vec![b'a'; MAX_MESSAGE_BYTES as usize * 2];
+      let mut reader = BufReader::new(&line[..]);
+
+      buffer.clear();
+      assert_eq!(read_message(&mut reader, &mut buffer).await.unwrap(), None);
+      assert_eq!(buffer.len(), MAX_MESSAGE_BYTES as usize);
+    }
+
+    #[tokio::test]
+    async fn test_pings_are_answered() {
+      let node = Node::temp().await;
+      let mut rx = add_peer(&node, "alice", "10.0.0.1").await;
+
+      node.handle_message("alice", message(MessageData::Ping { nonce: 7 })).await;
+
+      let reply = rx.recv().await.unwrap();
+      assert!(matches!(reply.payload, MessageData::Pong { nonce: 7 }));
+    }
+
+    #[tokio::test]
+    async fn test_closed_handshake_is_an_error() {
+      let node = Node::temp().await;
+
+      let client = TcpStream::connect(node.get_local_addr()).await.unwrap();
+      drop(client);
+
+      let (stream, _) = node.listener.accept().await.unwrap();
+      let err = node.handle_incoming(stream).await.unwrap_err();
+
+      assert_eq!(err.to_string(), "Connection closed during the handshake");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_peer_is_torn_down_on_both_sides() {
+      let alice = Arc::new(Node::temp().await);
+      let bob = Arc::new(Node::temp().await);
+
+      let accepting = tokio::spawn({
+        let bob = bob.clone();
+        async move {
+          let (stream, _) = bob.listener.accept().await.unwrap();
+          bob.handle_incoming(stream).await.unwrap();
+        }
+      });
+
+      alice.connect_to_peer(&bob.get_local_addr()).await.unwrap();
+      accepting.await.unwrap();
+
+      assert!(alice.has_peer(&bob.node_id).await);
+      assert!(bob.has_peer(&alice.node_id).await);
+
+      // Bob hangs up, and Alice sees the connection close.
+      bob.rem_peer(&alice.node_id).await;
+
+      for _ in 0..50 {
+        if !alice.has_peer(&bob.node_id).await {
+          break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+      }
+
+      assert!(!alice.has_peer(&bob.node_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_peers_skip_the_outbound_cap() {
+      let mut alice = Node::temp().await;
+      alice.outbound = 0;
+
+      let bob = Arc::new(Node::temp().await);
+
+      let accepting = tokio::spawn({
+        let bob = bob.clone();
+        async move {
+          let (stream, _) = bob.listener.accept().await.unwrap();
+          bob.handle_incoming(stream).await.unwrap();
+        }
+      });
+
+      assert!(alice.connect_to_peer(&bob.get_local_addr()).await.is_err());
+
+      alice.connect_to_persistent_peer(&bob.get_local_addr()).await.unwrap();
+      accepting.await.unwrap();
+
+      assert!(alice.has_peer(&bob.node_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_peer_id_is_refused() {
+      let alice = Arc::new(Node::temp().await);
+      let bob = Node::temp().await;
+      let mut mallory = Node::temp().await;
+      mallory.node_id = bob.node_id.clone();
+
+      let accepting = tokio::spawn({
+        let alice = alice.clone();
+        async move {
+          let (stream, _) = alice.listener.accept().await.unwrap();
+          alice.handle_incoming(stream).await.unwrap();
+
+          let (stream, _) = alice.listener.accept().await.unwrap();
+          alice.handle_incoming(stream).await.is_ok()
+        }
+      });
+
+      bob.connect_to_peer(&alice.get_local_addr()).await.unwrap();
+      let _ = mallory.connect_to_peer(&alice.get_local_addr()).await;
+
+      assert!(!accepting.await.unwrap());
+
+      let peers = alice.peers.lock().await;
+      assert_eq!(peers.len(), 1);
+      assert_eq!(peers[&bob.node_id].conn, 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_node_says_goodbye() {
+      let alice = Arc::new(Node::temp().await);
+      let bob = Arc::new(Node::temp().await);
+
+      let accepting = tokio::spawn({
+        let bob = bob.clone();
+        async move {
+          let (stream, _) = bob.listener.accept().await.unwrap();
+          bob.handle_incoming(stream).await.unwrap();
+        }
+      });
+
+      alice.connect_to_peer(&bob.get_local_addr()).await.unwrap();
+      accepting.await.unwrap();
+
+      alice.stop();
+      assert!(alice.miner.is_stopped());
+
+      alice.close().await;
+      assert!(alice.get_peers().await.is_empty());
+      assert!(alice.addrs.lock().await.sample(1).contains(&bob.get_local_addr()));
+
+      for _ in 0..50 {
+        if !bob.has_peer(&alice.node_id).await {
+          break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+      }
+
+      assert!(!bob.has_peer(&alice.node_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_full_inbound_slots_evict_the_worst_peer() {
+      let mut node = Node::temp().await;
+      node.max_inbound = 2;
+
+      let _alice = add_peer(&node, "alice", "10.0.0.1").await;
+      let _mallory = add_peer(&node, "mallory", "10.0.0.2").await;
+      node.misbehaving("mallory", Misbehavior::Unsolicited).await;
+
+      assert!(node.make_room().await);
+      assert!(node.has_peer("alice").await);
+      assert!(!node.has_peer("mallory").await);
+    }
+
+    #[tokio::test]
+    async fn test_long_lived_peers_are_kept() {
+      let mut node = Node::temp().await;
+      node.max_inbound = 2;
+
+      let _alice = add_peer(&node, "alice", "10.0.0.1").await;
+      let _bob = add_peer(&node, "bob", "10.0.0.2").await;
+
+      assert!(!node.make_room().await);
+      assert_eq!(node.get_peers().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connections_per_address_are_limited() {
+      let mut node = Node::temp().await;
+      node.max_per_ip = 1;
+
+      let _alice = add_peer(&node, "alice", "127.0.0.1").await;
+
+      let _client = TcpStream::connect(node.get_local_addr()).await.unwrap();
+      let (stream, _) = node.listener.accept().await.unwrap();
+      let err = node.handle_incoming(stream).await.unwrap_err();
+
+      assert_eq!(err.to_string(), "Too many connections from 127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_node_does_not_dial_itself() {
+      let node = Node::temp().await;
+      node.addrs.lock().await.add(&node.get_local_addr());
+
+      let err = node.connect_to_peer(&node.get_local_addr()).await.unwrap_err();
+
+      assert_eq!(err.to_string(), "Cannot connect to self");
+      assert!(node.addrs.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_asks_for_the_next_block() {
+      let node = Node::temp().await;
+      let mut rx = add_peer(&node, "alice", "10.0.0.1").await;
+
+      node.sync().await;
+
+      let request = rx.recv().await.unwrap();
+      assert!(matches!(request.payload, MessageData::BlockRequest { index: 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_sync_stops_at_a_rejected_block() {
+      let node = Node::temp().await;
+      let mut rx = add_peer(&node, "alice", "10.0.0.1").await;
+
+      node.sync().await;
+      rx.recv().await.unwrap();
+
+      let post = BlockData::Post {
+        body:  "hello".to_string(),
+        reply: None,
+      };
+
+      // Doesn't go on top of the genesis block.
+      let mut block = Block::new(post.clone(), 1, "0".to_string());
+      block.public_key = Account::new(1).public_key();
+      block.signature  = Account::new(1).sign(&post);
+      block.mine_block(post.rules().difficulty);
+
+      node.handle_message("alice", message(MessageData::BlockResponse { block })).await;
+
+      assert_eq!(node.chain.reader().height(), 0);
+      assert!(timeout(Duration::from_millis(50), rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replies_go_to_the_connection() {
+      let node = Node::temp().await;
+      let mut rx = add_peer(&node, "alice", "10.0.0.1").await;
+
+      node.handle_message("alice", message(MessageData::BlockRequest { index: 0 })).await;
+
+      let reply = rx.recv().await.unwrap();
+      assert!(matches!(reply.payload, MessageData::BlockResponse { .. }));
+    }
 }
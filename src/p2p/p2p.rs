@@ -1,33 +1,97 @@
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio::time::sleep;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tracing::{error, info, info_span, warn, Instrument};
 use crate::p2p::node::Node;
 use crate::p2p::gossip;
 use crate::p2p::input;
-use crate::blockchain::block::Block;
-use crate::blockchain::chain::Blockchain;
+use crate::p2p::lan;
 use crate::p2p::message::MessageData;
 
+// How often a persistent peer is checked on.
+const PERSISTENT_CHECK: Duration = Duration::from_secs(5);
+
+// The delay before reconnecting to a persistent peer, doubled after every
+// failed attempt.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(5 * 60);
+
+/**
+ * How the p2p node is set up.
+ */
+#[derive(Debug, Clone)]
+pub struct P2pConfig {
+  pub addr:         String,
+  // Peers to stay connected to.
+  pub peers:        Vec<String>,
+  // Where the addresses of known peers are kept between runs.
+  pub address_book: PathBuf,
+  // Where banned addresses are kept between runs.
+  pub bans:         PathBuf,
+  // The number of outbound connections to keep up, and the most allowed.
+  pub outbound:     usize,
+  pub max_inbound:  usize,
+  // The most connections from one address.
+  pub max_per_ip:   usize,
+  // Whether to find peers on the local network.
+  pub lan:          bool,
+  // Whether to read commands from stdin.
+  pub console:      bool,
+}
+
 /**
- * Start the p2p node.
+ * Start the p2p node, and run it until the node is stopped. Mining is
+ * wound down and the peers told goodbye before it returns.
  */
-pub async fn start_p2p(chain: Arc<Mutex<Blockchain>>, addr: String, peers: Vec<String>) {
-  let node = Arc::new(Node::new(chain, addr).await);
+pub async fn start_p2p(node: Arc<Node>, config: P2pConfig) {
+  let mut persistent = vec![];
+
+  for peer in config.peers {
+    let connected = match node.connect_to_persistent_peer(&peer).await {
+      Ok(peer_id) => Some(peer_id),
+      Err(e)      => {
+        warn!(addr = %peer, error = %e, "Could not connect to a configured peer");
+        None
+      },
+    };
 
-  for peer in peers.clone() {
-    let _ = node.connect_to_peer(&peer).await;
+    persistent.push((peer, connected));
   }
 
   node.sync().await;
 
-  let _ = tokio::join!(
-    tokio::spawn(handle_mempool_blocks(node.clone())),
+  let mut tasks = vec![
     tokio::spawn(handle_incoming_messages(node.clone())),
     tokio::spawn(gossip::handle_peer_gossip(node.clone())),
-    tokio::spawn(input::handle_user_input(node.clone())),
-  );
+    tokio::spawn(gossip::handle_outbound_peers(node.clone())),
+  ];
+
+  for (peer, connected) in persistent {
+    tasks.push(tokio::spawn(handle_persistent_peer(node.clone(), peer, connected)));
+  }
+
+  if config.lan {
+    tasks.push(tokio::spawn(lan::handle_lan_discovery(node.clone())));
+  }
+
+  if config.console {
+    tasks.push(tokio::spawn(input::handle_user_input(node.clone())));
+  }
+
+  let mining = tokio::spawn(handle_mempool_blocks(node.clone()));
+
+  node.stopped().await;
+
+  for task in tasks {
+    task.abort();
+  }
+
+  let _ = mining.await;
+
+  node.close().await;
 }
 
 /**
@@ -35,23 +99,67 @@ pub async fn start_p2p(chain: Arc<Mutex<Blockchain>>, addr: String, peers: Vec<S
  */
 async fn handle_incoming_messages(node: Arc<Node>) {
   loop {
-    let (socket, _) = node.listener.accept().await.unwrap();
-    tokio::spawn(handle_client(node.clone(), socket));
+    match node.listener.accept().await {
+      Ok((socket, addr)) => {
+        let Ok(permit) = node.handshakes.clone().try_acquire_owned() else {
+          warn!(remote = %addr, "Too many pending connections, dropping");
+          continue;
+        };
+
+        let span = info_span!("incoming", remote = %addr);
+        tokio::spawn(handle_client(node.clone(), socket, permit).instrument(span));
+      },
+      // Running out of file descriptors passes, so wait and accept again.
+      Err(e) => {
+        error!(error = %e, "Could not accept a connection");
+        sleep(Duration::from_secs(1)).await;
+      },
+    }
   }
 }
 
 /**
- * Read messages from a connected peer.
+ * Read messages from a connected peer. The permit is held through the
+ * handshake.
  */
-async fn handle_client(node: Arc<Node>, stream: TcpStream) {
-  println!("incoming");
-
-  let _ = node.handle_incoming(stream).await;
+async fn handle_client(node: Arc<Node>, stream: TcpStream, _permit: OwnedSemaphorePermit) {
+  if let Err(e) = node.handle_incoming(stream).await {
+    info!(error = %e, "Rejected incoming connection");
+  }
 }
 
 /**
- * Handle a peer message.
+ * Stay connected to a configured peer, reconnecting with a growing delay
+ * whenever the connection drops.
  */
+async fn handle_persistent_peer(node: Arc<Node>, addr: String, mut peer_id: Option<String>) {
+  let mut delay = RECONNECT_MIN;
+
+  loop {
+    if let Some(id) = &peer_id {
+      if node.has_peer(id).await {
+        sleep(PERSISTENT_CHECK).await;
+        continue;
+      }
+    }
+
+    match node.connect_to_persistent_peer(&addr).await {
+      Ok(id) => {
+        peer_id = Some(id);
+        delay = RECONNECT_MIN;
+        continue;
+      },
+      Err(e) => {
+        warn!(%addr, error = %e, retry_in = delay.as_secs(), "Could not connect to a configured peer");
+      },
+    }
+
+    sleep(delay).await;
+    delay = (delay * 2).min(RECONNECT_MAX);
+  }
+}
+
+// Handle a peer message.
 // async fn handle_message(node: Arc<Node>, message: Message) {
 //   match message.payload {
 //     MessageData::Chat { message: msg } => {
@@ -117,44 +225,38 @@ async fn handle_client(node: Arc<Node>, stream: TcpStream) {
 // }
 
 /**
- * Handle pending blocks in the mempool.
+ * Handle pending blocks in the mempool, until the node is stopped. A block
+ * that was being mined then goes back in the mempool.
  */
 pub async fn handle_mempool_blocks(node: Arc<Node>) {
-  loop {
-    let block = {
-      let mut chain = node
-        .chain
-        .lock()
-        .await;
-      chain.mpool.pop()
-    };
-
-    // todo: cross-node race conditions (retry)
+  while !node.is_stopping() {
+    let block = node.chain
+      .write(|chain| chain.pop_mempool())
+      .await;
 
     if let Some(pending_block) = block {
-      println!("Processing block");
-
-      let mut chain = node.chain.lock().await;
-      let mut block = Block::next(
-        &chain.top_block(),
-        pending_block.data
-      );
-
-      block.timestamp  = pending_block.timestamp;
-      block.signature  = pending_block.signature;
-      block.public_key = pending_block.public_key;
+      info!("Mining a pending block");
 
-      block.mine_block();
-      chain.add_block(block.clone())
-        .unwrap_or_else(|e| println!("{}", e));
+      match node.miner.mine_pending(&node.chain, pending_block.clone()).await {
+        Some(block) => {
+          info!(hash = %block.hash, index = block.index, "Mined a pending block");
 
-      println!("Processed block: {:?}", block);
-
-      node.yell(&MessageData::BlockchainTx {
-        block,
-      }).await;
+          node.yell(&MessageData::BlockchainTx {
+            block,
+          }).await;
+        },
+        None if node.miner.is_stopped() => {
+          node.chain
+            .write(move |chain| chain.requeue_mempool(pending_block))
+            .await;
+        },
+        None => {},
+      }
     }
 
-    sleep(Duration::from_secs(1)).await;
+    tokio::select! {
+      _ = sleep(Duration::from_secs(1)) => {},
+      _ = node.stopped() => {},
+    }
   }
 }
@@ -1,21 +1,212 @@
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use crate::p2p::addrbook::dialable;
+use crate::p2p::message::{Handshake, Services};
+use crate::p2p::queue::{SendQueue, Traffic, TrafficStats};
 
+// The inbound peers connected longest that haven't misbehaved, which are
+// kept when making room for new peers.
+const PROTECTED: usize = 4;
+
+// The most new addresses one peer can add to the address book per window of
+// seconds, so that a single peer can't fill it.
+const GOSSIP_BUDGET: usize = 64;
+const GOSSIP_WINDOW: u64 = 10 * 60;
+
+/**
+ * Who opened the connection to a peer.
+ */
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+  Inbound,
+  Outbound,
+}
+
+/**
+ * A connected peer.
+ */
+#[derive(Debug)]
 pub struct Peer {
-  pub peer_id:   String,
-  pub peer_addr: String,
-  sender:   UnboundedSender<String>,
-  receiver: UnboundedReceiver<String>,
+  pub queue:        SendQueue,
+  pub traffic:      Arc<Traffic>,
+  // Tells this connection to the peer apart from earlier ones.
+  pub conn:         u64,
+  // The address the peer is connected from.
+  pub remote:       SocketAddr,
+  // The address the peer can be dialed on, when it is known.
+  pub addr:         Option<String>,
+  pub direction:    Direction,
+  // The protocol version agreed on in the handshake.
+  pub version:      u32,
+  pub handshake:    Handshake,
+  pub connected_at: u64,
+  // The highest block the peer is known to have.
+  pub tip_height:   u64,
+  // How badly the peer has behaved. It is banned at `BAN_THRESHOLD`.
+  pub score:        u32,
+  // The block indexes requested from the peer and not yet received.
+  pub requested:    HashSet<usize>,
+  // The new addresses the peer has gossiped since the start of the window.
+  gossiped:         usize,
+  gossip_since:     u64,
+  // The task writing the queue to the peer, which ends once the queue is
+  // dropped and written out.
+  pub writer:       Option<JoinHandle<()>>,
+}
+
+/**
+ * What is known about a peer, as shown to admins.
+ */
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+  pub peer_id:      String,
+  pub remote:       SocketAddr,
+  pub addr:         Option<String>,
+  pub direction:    Direction,
+  pub software:     String,
+  pub version:      u32,
+  pub services:     Services,
+  pub connected_at: u64,
+  // When the peer last sent a message, if it has.
+  pub last_message: Option<u64>,
+  pub tip_height:   u64,
+  pub score:        u32,
+  pub traffic:      TrafficStats,
 }
 
 impl Peer {
-  pub fn new(peer_id: String, peer_addr: String) -> Self {
-    let (sender, receiver) = unbounded_channel();
+  pub fn new(
+    handshake: Handshake,
+    version: u32,
+    remote: SocketAddr,
+    direction: Direction,
+    queue: SendQueue,
+    conn: u64,
+  ) -> Self {
+    // A peer that was dialed is reachable where it was dialed. One that
+    // connected to us accepts connections on the port it advertises, at the
+    // address it connected from.
+    let addr = match direction {
+      Direction::Outbound => dialable(&remote.to_string()),
+      Direction::Inbound  => handshake.listen_addr
+        .parse::<SocketAddr>()
+        .ok()
+        .and_then(|listen| dialable(&SocketAddr::new(remote.ip(), listen.port()).to_string())),
+    };
 
     Self {
-      peer_id,
-      peer_addr,
-      sender,
-      receiver,
+      queue,
+      traffic:      Arc::default(),
+      conn,
+      remote,
+      addr,
+      direction,
+      version,
+      tip_height:   handshake.tip_height,
+      handshake,
+      connected_at: now(),
+      score:        0,
+      requested:    HashSet::new(),
+      gossiped:     0,
+      gossip_since: now(),
+      writer:       None,
+    }
+  }
+
+  pub fn peer_id(&self) -> &str {
+    &self.handshake.peer_id
+  }
+
+  pub fn ip(&self) -> IpAddr {
+    self.remote.ip()
+  }
+
+  pub fn is_outbound(&self) -> bool {
+    self.direction == Direction::Outbound
+  }
+
+  /**
+   * Note that the peer has a block, moving its tip up.
+   */
+  pub fn has_block(&mut self, index: u64) {
+    self.tip_height = self.tip_height.max(index);
+  }
+
+  /**
+   * The number of new addresses the peer can still add to the address book
+   * in this window.
+   */
+  pub fn gossip_budget(&mut self) -> usize {
+    let now = now();
+
+    if now >= self.gossip_since + GOSSIP_WINDOW {
+      self.gossiped = 0;
+      self.gossip_since = now;
+    }
+
+    GOSSIP_BUDGET - self.gossiped
+  }
+
+  /**
+   * Count new addresses gossiped by the peer against its budget.
+   */
+  pub fn spend_gossip(&mut self, added: usize) {
+    self.gossiped = (self.gossiped + added).min(GOSSIP_BUDGET);
+  }
+
+  pub fn info(&self) -> PeerInfo {
+    let traffic = self.traffic.stats();
+
+    PeerInfo {
+      peer_id:      self.peer_id().to_string(),
+      remote:       self.remote,
+      addr:         self.addr.clone(),
+      direction:    self.direction,
+      software:     self.handshake.software.clone(),
+      version:      self.version,
+      services:     self.handshake.services,
+      connected_at: self.connected_at,
+      last_message: self.traffic.last_received(),
+      tip_height:   self.tip_height,
+      score:        self.score,
+      traffic,
     }
   }
 }
+
+/**
+ * Pick the inbound peer to drop when the inbound slots are full. The longest
+ * connected peers that have behaved are kept, and of the rest the worst
+ * behaved, then the newest, goes first. Returns `None` when every peer is
+ * kept.
+ */
+pub fn eviction_candidate(peers: &[&Peer]) -> Option<String> {
+  let mut peers = peers.to_vec();
+  peers.sort_by_key(|peer| (peer.connected_at, peer.conn));
+
+  let mut protected = 0;
+  peers.retain(|peer| {
+    let protect = protected < PROTECTED && peer.score == 0;
+    if protect {
+      protected += 1;
+    }
+    !protect
+  });
+
+  peers
+    .into_iter()
+    .max_by_key(|peer| (peer.score, peer.connected_at, peer.conn))
+    .map(|peer| peer.peer_id().to_string())
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards")
+    .as_secs()
+}
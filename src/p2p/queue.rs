@@ -0,0 +1,304 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{Duration, Instant};
+use crate::metrics::metrics;
+use crate::p2p::message::{Message, MessageData};
+
+// The most messages waiting to be written to a peer, per priority.
+const HIGH_CAPACITY: usize = 256;
+const LOW_CAPACITY: usize = 64;
+
+// How long a peer's queue can stay full before the peer is dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Which queue a message waits in. High priority messages are written first.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+  High,
+  Low,
+}
+
+impl MessageData {
+  /**
+   * Blocks, sync traffic and keepalives keep the chain moving. Gossip and
+   * chat can wait, or be lost.
+   */
+  pub fn priority(&self) -> Priority {
+    match self {
+      MessageData::Handshake { .. }
+      | MessageData::BlockchainTx { .. }
+      | MessageData::BlockRequest { .. }
+      | MessageData::BlockResponse { .. }
+      | MessageData::Ping { .. }
+      | MessageData::Pong { .. }
+      | MessageData::Goodbye { .. } => Priority::High,
+      MessageData::PeerDiscovery { .. }
+      | MessageData::PeerGossip { .. }
+      | MessageData::Chat { .. } => Priority::Low,
+    }
+  }
+}
+
+/**
+ * What happened to a message put on a queue.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Push {
+  Queued,
+  // The queue was full and the message was dropped.
+  Dropped,
+  // The queue has been full for too long, the peer should be dropped.
+  Stalled,
+  // The writer has stopped.
+  Closed,
+}
+
+/**
+ * The sending end of the queues to a peer.
+ */
+#[derive(Debug)]
+pub struct SendQueue {
+  high:  Lane,
+  low:   Lane,
+  stall: Duration,
+}
+
+/**
+ * The sending end of the queue for one priority.
+ */
+#[derive(Debug)]
+struct Lane {
+  sender:     mpsc::Sender<Message>,
+  // When the queue was first found full, since the last message that fit.
+  full_since: Option<Instant>,
+}
+
+/**
+ * The receiving end of the queues to a peer, read by its writer.
+ */
+#[derive(Debug)]
+pub struct RecvQueue {
+  high: mpsc::Receiver<Message>,
+  low:  mpsc::Receiver<Message>,
+}
+
+/**
+ * Create the queues to a peer.
+ */
+pub fn queue() -> (SendQueue, RecvQueue) {
+  queue_with(HIGH_CAPACITY, LOW_CAPACITY, STALL_TIMEOUT)
+}
+
+fn queue_with(high: usize, low: usize, stall: Duration) -> (SendQueue, RecvQueue) {
+  let (high_tx, high_rx) = mpsc::channel(high);
+  let (low_tx, low_rx) = mpsc::channel(low);
+
+  let lane = |sender| Lane {
+    sender,
+    full_since: None,
+  };
+
+  let send = SendQueue {
+    high: lane(high_tx),
+    low:  lane(low_tx),
+    stall,
+  };
+
+  let recv = RecvQueue {
+    high: high_rx,
+    low:  low_rx,
+  };
+
+  (send, recv)
+}
+
+impl SendQueue {
+  /**
+   * Put a message on the queue for its priority, without waiting.
+   */
+  pub fn push(&mut self, message: Message) -> Push {
+    let lane = match message.payload.priority() {
+      Priority::High => &mut self.high,
+      Priority::Low  => &mut self.low,
+    };
+
+    match lane.sender.try_send(message) {
+      Ok(()) => {
+        lane.full_since = None;
+        Push::Queued
+      },
+      Err(TrySendError::Closed(_)) => Push::Closed,
+      Err(TrySendError::Full(_)) => {
+        let since = *lane.full_since.get_or_insert_with(Instant::now);
+
+        if since.elapsed() >= self.stall {
+          Push::Stalled
+        } else {
+          Push::Dropped
+        }
+      },
+    }
+  }
+}
+
+impl RecvQueue {
+  /**
+   * Take the next message, high priority first. Returns `None` once the
+   * sending end is dropped and the queues are empty.
+   */
+  pub async fn recv(&mut self) -> Option<Message> {
+    tokio::select! {
+      biased;
+      Some(message) = self.high.recv() => Some(message),
+      Some(message) = self.low.recv() => Some(message),
+      else => None,
+    }
+  }
+}
+
+/**
+ * Counters of the traffic over a peer connection.
+ */
+#[derive(Debug, Default)]
+pub struct Traffic {
+  messages_sent:     AtomicU64,
+  bytes_sent:        AtomicU64,
+  messages_received: AtomicU64,
+  bytes_received:    AtomicU64,
+  dropped:           AtomicU64,
+  // When the last message came in, in seconds since the epoch. Zero until
+  // one has.
+  last_received:     AtomicU64,
+}
+
+/**
+ * The traffic counters at one point in time.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TrafficStats {
+  pub messages_sent:     u64,
+  pub bytes_sent:        u64,
+  pub messages_received: u64,
+  pub bytes_received:    u64,
+  // Messages dropped because the queue to the peer was full.
+  pub dropped:           u64,
+}
+
+impl Traffic {
+  pub fn sent(&self, bytes: usize) {
+    self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    metrics().bytes_sent(bytes);
+  }
+
+  pub fn received(&self, bytes: usize) {
+    self.messages_received.fetch_add(1, Ordering::Relaxed);
+    self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    metrics().bytes_received(bytes);
+
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .expect("Time went backwards")
+      .as_secs();
+
+    self.last_received.store(now, Ordering::Relaxed);
+  }
+
+  /**
+   * When the last message came in, if one has.
+   */
+  pub fn last_received(&self) -> Option<u64> {
+    match self.last_received.load(Ordering::Relaxed) {
+      0    => None,
+      time => Some(time),
+    }
+  }
+
+  pub fn dropped(&self) {
+    self.dropped.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn stats(&self) -> TrafficStats {
+    TrafficStats {
+      messages_sent:     self.messages_sent.load(Ordering::Relaxed),
+      bytes_sent:        self.bytes_sent.load(Ordering::Relaxed),
+      messages_received: self.messages_received.load(Ordering::Relaxed),
+      bytes_received:    self.bytes_received.load(Ordering::Relaxed),
+      dropped:           self.dropped.load(Ordering::Relaxed),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: MessageData) -> Message {
+      Message {
+        sender: "node".to_string(),
+        payload,
+      }
+    }
+
+    fn chat(text: &str) -> Message {
+      message(MessageData::Chat { message: text.to_string() })
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_goes_first() {
+      let (mut send, mut recv) = queue();
+
+      send.push(chat("hello"));
+      send.push(message(MessageData::BlockRequest { index: 1 }));
+
+      assert!(matches!(recv.recv().await.unwrap().payload, MessageData::BlockRequest { index: 1 }));
+      assert!(matches!(recv.recv().await.unwrap().payload, MessageData::Chat { .. }));
+
+      drop(send);
+      assert!(recv.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_then_stalls() {
+      let (mut send, mut recv) = queue_with(1, 1, Duration::from_millis(50));
+
+      assert_eq!(send.push(chat("one")), Push::Queued);
+      assert_eq!(send.push(chat("two")), Push::Dropped);
+
+      // Blocks have their own queue.
+      assert_eq!(send.push(message(MessageData::BlockRequest { index: 1 })), Push::Queued);
+
+      tokio::time::sleep(Duration::from_millis(60)).await;
+      assert_eq!(send.push(chat("three")), Push::Stalled);
+
+      // Draining the queue clears the stall.
+      recv.recv().await.unwrap();
+      recv.recv().await.unwrap();
+      assert_eq!(send.push(chat("four")), Push::Queued);
+      assert_eq!(send.push(chat("five")), Push::Dropped);
+    }
+
+    #[test]
+    fn test_traffic_is_counted() {
+      let traffic = Traffic::default();
+
+      traffic.sent(10);
+      traffic.sent(5);
+      assert_eq!(traffic.last_received(), None);
+      traffic.received(7);
+      traffic.dropped();
+
+      assert!(traffic.last_received().is_some());
+      assert_eq!(traffic.stats(), TrafficStats {
+        messages_sent:     2,
+        bytes_sent:        15,
+        messages_received: 1,
+        bytes_received:    7,
+        dropped:           1,
+      });
+    }
+}